@@ -1,14 +1,21 @@
-use chrono::{NaiveDateTime, Utc, Duration};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc, Duration, Datelike, Timelike};
 use std::collections::{HashMap, HashSet};
 use thiserror::Error;
-use actix_web::{web, App, HttpServer, HttpResponse, get, ResponseError};
-use serde::Serialize;
+use actix_web::{web, middleware, App, HttpServer, HttpRequest, HttpResponse, get, post, delete, ResponseError};
+use actix_web::http::header;
+use actix_web::dev::Service;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Instant;
 use actix_cors::Cors;
 use ical::IcalParser;
-use ical::parser::ical::component::IcalEvent;
+use ical::parser::ical::component::{IcalCalendar, IcalEvent};
 use ical::parser::ParserError;
-use regex::Regex;
+use rrule::RRuleSet;
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use futures::stream::{self, StreamExt};
 use tokio::time;
 use tokio::sync::Mutex;
 use serde_json::Error as SerdeError;
@@ -17,6 +24,32 @@ const START_WEEK_OFFSET: i64 = 2;
 const END_WEEK_OFFSET: i64 = 8;
 const ICAL_DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
 const UBS_DATE_FORMAT: &str = "%Y-%m-%d";
+const UBS_CALENDAR_URL: &str = "https://planning.univ-ubs.fr/jsp/custom/modules/plannings/anonymous_cal.jsp";
+const DEFAULT_REFRESH_SECS: u64 = 3600;
+const DEFAULT_BIND_ADDR: &str = "127.0.0.1:8080";
+const DEFAULT_CACHE_FILE: &str = "free-room-cache.json";
+const DEFAULT_CACHE_MAX_AGE_SECS: i64 = 24 * 3600;
+const DEFAULT_FETCH_TIMEOUT_SECS: u64 = 30;
+const FETCH_ATTEMPTS: u32 = 3;
+const FETCH_BACKOFF_SECS: u64 = 1;
+const DEFAULT_QUIET_INTERVAL_SECS: u64 = 3 * 3600;
+const FETCH_CONCURRENCY: usize = 8;
+const DEFAULT_BUILDINGS: [&str; 2] = ["V-A", "V-B"];
+const DEFAULT_SLOWEST_RESOURCES: usize = 10;
+const DEFAULT_TIMEZONE: Tz = chrono_tz::Europe::Paris;
+const DEFAULT_OPERATING_HOURS: (u32, u32) = (8, 20);
+const DEFAULT_SKEW_WARNING_SECS: i64 = 120;
+const MAX_RECURRENCES: u16 = 500;
+// Keeps `now + horizon` far from overflowing; still well beyond any fetched window.
+const MAX_HORIZON_SECS: i64 = 100 * 365 * 24 * 3600;
+const DEFAULT_REFRESH_COOLDOWN_SECS: i64 = 300;
+const DEFAULT_INITIAL_REFRESH_DELAY_SECS: u64 = 60;
+const DEFAULT_CORS_MAX_AGE_SECS: usize = 3600;
+const DEFAULT_CAPACITY_INTERVAL_MINUTES: i64 = 15;
+const CAPACITY_HOURS: i64 = 24;
+const DEFAULT_MAX_CONCURRENT_PER_CLIENT: usize = 16;
+const DEFAULT_HEALTH_GRACE_SECS: i64 = 3 * 3600;
+const DEFAULT_HEALTH_MAX_FAILURES: usize = 2;
 const RESOURCES: [i32; 118] = [
     726, 1508, 730, 1649, 731, 1680, 706, 1698, 733, 1715,
     707, 5805, 3400, 3403, 3404, 7957, 7958, 4816, 7834, 7835,
@@ -40,8 +73,6 @@ enum AppError {
     ChronoParse(#[from] chrono::format::ParseError),
     #[error("std io error")]
     Std(#[from] std::io::Error),
-    #[error("regex error")]
-    Regex(#[from] regex::Error),
     #[error("parser error")]
     ParserError,
     #[error("parse error")]
@@ -50,6 +81,68 @@ enum AppError {
     SerdeJson(#[from] SerdeError),
     #[error("ical parsing error")]
     IcalParse(#[from] ParserError),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("missing or invalid admin token")]
+    Unauthorized,
+    #[error("admin api is disabled")]
+    AdminDisabled,
+    #[error("invalid resource id {0}")]
+    InvalidResource(i32),
+    #[error("resource {0} is already monitored")]
+    DuplicateResource(i32),
+    #[error("resource {0} is not monitored")]
+    UnknownResource(i32),
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("unknown room {0}")]
+    UnknownRoom(String),
+    #[error("recurrence rule error")]
+    Recurrence(#[from] rrule::RRuleError),
+    #[error("refresh available in {0} seconds")]
+    RefreshCooldown(i64),
+    #[error("a refresh is already running")]
+    RefreshInProgress,
+    #[error("refresh task failed")]
+    RefreshFailed,
+    #[error("too many concurrent requests")]
+    TooManyRequests,
+}
+
+impl AppError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            AppError::Network(e) => e.status().is_none_or(|status| status.is_server_error()),
+            _ => false,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::Network(e) if e.is_timeout() => "timeout",
+            AppError::Network(e) if e.is_status() => "upstream_status",
+            AppError::Network(_) => "network",
+            AppError::ChronoParse(_) => "date_parse",
+            AppError::Std(_) => "io",
+            AppError::ParserError => "empty_calendar",
+            AppError::ParseError => "missing_property",
+            AppError::SerdeJson(_) => "json",
+            AppError::IcalParse(_) => "ical_parse",
+            AppError::Config(_) => "config",
+            AppError::Unauthorized => "unauthorized",
+            AppError::AdminDisabled => "admin_disabled",
+            AppError::InvalidResource(_) => "invalid_resource",
+            AppError::DuplicateResource(_) => "duplicate_resource",
+            AppError::UnknownResource(_) => "unknown_resource",
+            AppError::InvalidQuery(_) => "invalid_query",
+            AppError::UnknownRoom(_) => "unknown_room",
+            AppError::Recurrence(_) => "recurrence",
+            AppError::RefreshCooldown(_) => "refresh_cooldown",
+            AppError::RefreshInProgress => "refresh_in_progress",
+            AppError::RefreshFailed => "refresh_failed",
+            AppError::TooManyRequests => "too_many_requests",
+        }
+    }
 }
 
 impl ResponseError for AppError {
@@ -58,48 +151,184 @@ impl ResponseError for AppError {
             AppError::Network(_) => HttpResponse::ServiceUnavailable().json("Network error"),
             AppError::ChronoParse(_) => HttpResponse::InternalServerError().json("Chrono parse error"),
             AppError::Std(_) => HttpResponse::InternalServerError().json("Std error"),
-            AppError::Regex(_) => HttpResponse::BadRequest().json("Invalid input"),
             AppError::ParserError => HttpResponse::BadRequest().json("Parser error"),
             AppError::ParseError => HttpResponse::BadRequest().json("Parse error"),
             AppError::SerdeJson(_) => HttpResponse::InternalServerError().json("Serde json error"),
             AppError::IcalParse(_) => HttpResponse::InternalServerError().json("Ical parse error"),
+            AppError::Config(_) => HttpResponse::InternalServerError().json("Configuration error"),
+            AppError::Unauthorized => HttpResponse::Unauthorized().json("Unauthorized"),
+            AppError::AdminDisabled => HttpResponse::Forbidden().json("Admin API disabled"),
+            AppError::InvalidResource(_) => HttpResponse::BadRequest().json("Invalid resource id"),
+            AppError::DuplicateResource(_) => HttpResponse::Conflict().json("Resource already monitored"),
+            AppError::UnknownResource(_) => HttpResponse::NotFound().json("Resource not monitored"),
+            AppError::InvalidQuery(ref message) => HttpResponse::BadRequest().json(message),
+            AppError::UnknownRoom(_) => HttpResponse::NotFound().json("Unknown room"),
+            AppError::Recurrence(_) => HttpResponse::InternalServerError().json("Recurrence rule error"),
+            AppError::RefreshCooldown(secs) => HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, secs.to_string()))
+                .json(format!("Refresh available in {} seconds", secs)),
+            AppError::RefreshInProgress => HttpResponse::Conflict().json("Refresh already running"),
+            AppError::RefreshFailed => HttpResponse::InternalServerError().json("Refresh failed"),
+            AppError::TooManyRequests => HttpResponse::TooManyRequests().json("Too many concurrent requests"),
         }
     }
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct RoomKey {
+    building: Option<String>,
+    name: String,
+}
+
+impl RoomKey {
+    // A name that carries its building ("V-A 12") is split on its first space, a bare name ("12")
+    // takes the building of the resource that listed it when known and stays name-only otherwise.
+    fn new(room_name: &str, key_by_building: bool, resource_building: Option<&str>) -> Self {
+        let room_name = room_name.trim();
+        if !key_by_building {
+            return RoomKey { building: None, name: room_name.to_string() };
+        }
+        match room_name.split_once(' ') {
+            Some((building, name)) if is_building(building) => RoomKey {
+                building: Some(building.to_string()),
+                name: name.to_string(),
+            },
+            _ => RoomKey {
+                building: resource_building.map(str::to_string),
+                name: room_name.to_string(),
+            },
+        }
+    }
+
+    fn display_name(&self) -> String {
+        match &self.building {
+            Some(building) => format!("{} {}", building, self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+fn is_building(value: &str) -> bool {
+    value.split_once('-').is_some_and(|(campus, block)| {
+        !campus.is_empty() && !block.is_empty() && campus.chars().chain(block.chars()).all(|c| c.is_ascii_alphabetic())
+    })
+}
+
+#[derive(Clone)]
 struct Room {
     name: String,
-    #[serde(skip_serializing)]
+    building: Option<String>,
     slots: HashSet<(i64, i64)>,
-    availability: Vec<(i64, i64)>,
+    resources: HashSet<i32>,
+    window_end: i64,
 }
 
 impl Room {
-    fn new(name: String) -> Self {
+    fn new(name: String, building: Option<String>) -> Self {
         Room {
             name,
+            building,
             slots: HashSet::new(),
-            availability: Vec::new(),
+            resources: HashSet::new(),
+            window_end: 0,
         }
     }
 
-    fn compute_availability(&mut self, current_timestamp: i64) {
-        let mut sorted_slots: Vec<_> = self.slots.iter().cloned().collect();
-        sorted_slots.sort_by(|a, b| a.0.cmp(&b.0));
-
-        self.availability.clear();
+    // Free windows are computed per request into a fresh list, so clipping one response never
+    // leaks into the shared room.
+    fn availability(&self, current_timestamp: i64) -> Vec<(i64, i64)> {
+        let mut availability = Vec::new();
         let mut last_end = current_timestamp;
-        for &(start, end) in &sorted_slots {
+        for (start, end) in self.busy_intervals() {
+            if start >= self.window_end {
+                break;
+            }
             if start > last_end {
-                self.availability.push((last_end, start));
+                availability.push((last_end, start));
             }
             if end > last_end {
                 last_end = end;
             }
         }
 
-        self.availability.push((last_end, current_timestamp));
+        if last_end < self.window_end {
+            availability.push((last_end, self.window_end));
+        }
+        availability
+    }
+
+    fn room_number(&self) -> Option<u32> {
+        let digits: String = self.name
+            .chars()
+            .skip_while(|c| !c.is_ascii_digit())
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        digits.parse().ok()
+    }
+
+    // Duplicate, overlapping and back-to-back bookings collapse into one interval; empty ones are dropped.
+    fn busy_intervals(&self) -> Vec<(i64, i64)> {
+        let mut intervals: Vec<(i64, i64)> = Vec::new();
+        for (start, end) in self.sorted_slots().into_iter().filter(|&(start, end)| start < end) {
+            match intervals.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => intervals.push((start, end)),
+            }
+        }
+        intervals
+    }
+
+    fn availability_events(&self, reference: i64, until: Option<i64>) -> Vec<AvailabilityEvent> {
+        let mut busy_now = false;
+        let mut events = Vec::new();
+        for (start, end) in self.busy_intervals() {
+            if end <= reference {
+                continue;
+            }
+            if start <= reference {
+                busy_now = true;
+            } else {
+                events.push(AvailabilityEvent { t: start, kind: "busy" });
+            }
+            events.push(AvailabilityEvent { t: end, kind: "free" });
+        }
+        if let Some(until) = until {
+            events.retain(|event| event.t < until);
+        }
+        events.insert(0, AvailabilityEvent { t: reference, kind: if busy_now { "busy" } else { "free" } });
+        events
+    }
+
+    fn upcoming_slots(&self, timestamp: i64) -> usize {
+        self.slots.iter().filter(|&&(_, end)| end > timestamp).count()
+    }
+
+    fn busy_since(&self, timestamp: i64) -> Option<i64> {
+        self.slots
+            .iter()
+            .filter(|&&(start, end)| start <= timestamp && timestamp < end)
+            .map(|&(start, _)| start)
+            .min()
+    }
+
+    fn busy_seconds(&self, from: i64, to: i64) -> i64 {
+        let mut busy = 0;
+        let mut last_end = from;
+        for (start, end) in self.sorted_slots() {
+            let start = start.max(last_end);
+            let end = end.min(to);
+            if end > start {
+                busy += end - start;
+                last_end = end;
+            }
+        }
+        busy
+    }
+
+    fn sorted_slots(&self) -> Vec<(i64, i64)> {
+        let mut slots: Vec<_> = self.slots.iter().cloned().collect();
+        slots.sort();
+        slots
     }
 }
 
@@ -109,158 +338,2808 @@ struct RoomAvailability {
     status: String,
     duration: i64,
     open: bool,
+    busy_since: Option<i64>,
+    confidence: f64,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), AppError> {
-    let rooms = Arc::new(Mutex::new(HashMap::new()));
-    let rooms_clone = rooms.clone();
+#[derive(Serialize)]
+struct RoomDetail {
+    #[serde(flatten)]
+    availability_info: RoomAvailability,
+    availability: Vec<(i64, i64)>,
+}
 
-    tokio::spawn(async move {
-        let mut interval = time::interval(time::Duration::from_secs(3600));
-        loop {
-            interval.tick().await;
-            update_rooms(&rooms_clone).await;
+#[derive(Serialize)]
+#[serde(untagged)]
+enum RoomWindows {
+    Windows(Vec<(i64, i64)>),
+    WithCounts {
+        availability: Vec<(i64, i64)>,
+        upcoming_slots: usize,
+    },
+    Events {
+        reference: i64,
+        events: Vec<AvailabilityEvent>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        upcoming_slots: Option<usize>,
+    },
+}
+
+#[derive(Serialize)]
+struct AvailabilityEvent {
+    t: i64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+#[derive(Serialize)]
+struct RoomTransition {
+    name: String,
+    from_status: String,
+    to_status: String,
+    transition_at: Option<i64>,
+}
+
+impl RoomAvailability {
+    fn next_free_in(&self) -> i64 {
+        match (self.status.as_str(), self.duration) {
+            ("available", _) => 0,
+            (_, duration) if duration >= 0 => duration,
+            _ => i64::MAX,
         }
-    });
+    }
+}
 
-    let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+#[derive(Serialize)]
+struct NextOpening {
+    opens_at: i64,
+    closes_at: i64,
+    rooms: Vec<RoomAvailability>,
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .wrap(
-                Cors::permissive()
-            )
-            .app_data(web::Data::new(rooms.clone()))
-            .service(get_all_rooms_info)
-            .service(get_rooms_availability)
-    })
-        .bind(format!("127.0.0.1:{}", port))?
-        .run()
-        .await?;
+#[derive(Serialize)]
+struct CapacitySample {
+    t: i64,
+    free_count: usize,
+}
 
-    Ok(())
+#[derive(Serialize)]
+struct RoomSuggestion {
+    name: String,
+    status: String,
+    available_at: i64,
+    duration: i64,
+}
+
+#[derive(Serialize)]
+struct RefreshResult {
+    refreshed_at: i64,
+    rooms: usize,
+    duration_ms: u64,
+}
+
+#[derive(Serialize)]
+struct RoomOccupancy {
+    name: String,
+    average_occupancy: f64,
+    days: usize,
 }
 
-async fn update_rooms(rooms: &Arc<Mutex<HashMap<String, Room>>>) {
-    let start_date = Utc::now().naive_utc().date() - Duration::weeks(START_WEEK_OFFSET);
-    let end_date = start_date + Duration::weeks(END_WEEK_OFFSET);
+#[derive(Serialize)]
+struct RoomsResponse<T: Serialize> {
+    generated_at: i64,
+    last_refresh: Option<i64>,
+    rooms: T,
+}
+
+#[derive(Serialize)]
+struct Export {
+    generated_at: i64,
+    refreshed_at: Option<i64>,
+    rooms: Vec<ExportRoom>,
+}
+
+#[derive(Serialize)]
+struct ExportRoom {
+    name: String,
+    building: Option<String>,
+    resources: Vec<i32>,
+    slots: Vec<(i64, i64)>,
+    availability: Vec<(i64, i64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ResourceEntry {
+    Id(i32),
+    WithBuilding { id: i32, building: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct RoomCache {
+    saved_at: i64,
+    rooms: Vec<CachedRoom>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedRoom {
+    name: String,
+    resources: Vec<i32>,
+    window_end: i64,
+    slots: Vec<(i64, i64)>,
+}
+
+#[derive(Serialize, Clone)]
+struct ResourceTiming {
+    resource: i32,
+    duration_ms: u64,
+    events: usize,
+    skipped: usize,
+    source: &'static str,
+}
+
+#[derive(Serialize, Clone)]
+struct ResourceFailure {
+    resource: i32,
+    code: &'static str,
+    message: String,
+    failed_at: i64,
+}
+
+#[derive(Default)]
+struct CrawlStatus {
+    resource_timings: HashMap<i32, ResourceTiming>,
+    clock_skews: HashMap<i32, i64>,
+    last_refresh: Option<i64>,
+    last_refresh_started: Option<i64>,
+    window: Option<(NaiveDate, NaiveDate)>,
+    last_successes: HashMap<i32, i64>,
+    resource_failures: HashMap<i32, ResourceFailure>,
+    refreshing: bool,
+    last_successful_refresh: Option<i64>,
+    last_refresh_errors: usize,
+    failed_refreshes: usize,
+}
+
+impl CrawlStatus {
+    fn confidence(&self, room: &Room, now: i64, fresh_secs: i64) -> f64 {
+        let freshness = match room.resources.iter().filter_map(|resource| self.last_successes.get(resource)).max() {
+            Some(&success) if now - success <= fresh_secs => 1.0,
+            Some(&success) if now - success <= 86400 => 0.5,
+            _ => 0.0,
+        };
+        let skipped = room.resources
+            .iter()
+            .any(|resource| self.resource_timings.get(resource).is_some_and(|timing| timing.skipped > 0));
+        let parsing = if room.resources.iter().any(|resource| self.resource_failures.contains_key(resource)) {
+            0.5
+        } else if skipped {
+            0.75
+        } else {
+            1.0
+        };
+        let plausibility = if room.slots.is_empty() { 0.5 } else { 1.0 };
+        freshness * parsing * plausibility
+    }
+
+    // Refreshes where every resource failed are tolerated until there are more than `max_failures` of
+    // them in a row and the last success is older than `grace_secs`, so upstream blips don't flap health.
+    fn healthy(&self, now: i64, grace_secs: i64, max_failures: usize) -> bool {
+        match self.last_successful_refresh {
+            Some(success) => self.failed_refreshes <= max_failures || now - success <= grace_secs,
+            None => false,
+        }
+    }
 
-    for resource in RESOURCES.iter() {
-        let mut rooms_guard = rooms.lock().await;
-        if let Err(e) = process_resource(resource, &mut rooms_guard, &start_date, &end_date).await {
-            eprintln!("Error processing resource {}: {}", resource, e);
+    fn forget_resource(&mut self, resource: i32) {
+        self.resource_timings.remove(&resource);
+        self.clock_skews.remove(&resource);
+        self.last_successes.remove(&resource);
+        self.resource_failures.remove(&resource);
+    }
+
+    fn try_start_refresh(&mut self, now: i64, cooldown: i64) -> Result<(), AppError> {
+        if self.refreshing {
+            return Err(AppError::RefreshInProgress);
+        }
+        if let Some(started) = self.last_refresh_started {
+            let next_allowed = started + cooldown;
+            if now < next_allowed {
+                return Err(AppError::RefreshCooldown(next_allowed - now));
+            }
         }
+        self.last_refresh_started = Some(now);
+        Ok(())
+    }
+
+    fn clock_skew(&self) -> Option<i64> {
+        median(self.clock_skews.values().cloned().collect())
+    }
+
+    fn slowest_resources(&self, count: usize) -> Vec<ResourceTiming> {
+        let mut timings: Vec<_> = self.resource_timings.values().cloned().collect();
+        timings.sort_by(|a, b| b.duration_ms.cmp(&a.duration_ms).then(a.resource.cmp(&b.resource)));
+        timings.truncate(count);
+        timings
     }
 }
 
-#[get("/all")]
-async fn get_all_rooms_info(
-    data: web::Data<Arc<Mutex<HashMap<String, Room>>>>
-) -> Result<HttpResponse, AppError> {
-    let mut rooms = HashMap::new();
-    let regex = Regex::new(r"^\bV-[AB]\s?\d*?\b$")?;
-    for room in data.lock().await.values_mut() {
-        if regex.is_match(&room.name) && !room.availability.is_empty() {
-            room.compute_availability(Utc::now().naive_utc().timestamp());
-            rooms.insert(room.name.clone(), room.availability.clone());
+// Clears the refreshing flag when a refresh ends, including when it panics or its future is dropped
+// mid-crawl (e.g. at shutdown), so scheduled refreshes are never locked out.
+struct RefreshGuard {
+    status: Arc<Mutex<CrawlStatus>>,
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        match self.status.try_lock() {
+            Ok(mut status) => status.refreshing = false,
+            Err(_) => {
+                let status = self.status.clone();
+                tokio::spawn(async move {
+                    status.lock().await.refreshing = false;
+                });
+            }
         }
     }
-    let rooms_json = serde_json::to_string(&rooms)?;
-    Ok(HttpResponse::Ok().content_type("application/json").body(rooms_json))
 }
 
-#[get("/lite/{hour_offset}")]
-async fn get_rooms_availability(
-    data: web::Data<Arc<Mutex<HashMap<String, Room>>>>,
-    path: web::Path<i64>,
-) -> Result<HttpResponse, AppError> {
-    let mut rooms = data.lock().await;
-    let offset = path.into_inner() * 3600;
-    let current_timestamp = Utc::now().naive_utc().timestamp() + offset;
-    let mut room_availabilities = Vec::new();
-    let regex = Regex::new(r"^\bV-[AB]\s?\d*?\b$")?;
+#[derive(Serialize)]
+struct Health {
+    ready: bool,
+    rooms: usize,
+    last_success: Option<i64>,
+    errors: usize,
+    failed_refreshes: usize,
+}
 
-    for (name, room) in rooms.iter_mut() {
-        if regex.is_match(name) {
-            room.compute_availability(current_timestamp);
-            let availability_info = calculate_room_availability(room, current_timestamp)?;
-            room_availabilities.push(RoomAvailability {
-                name: name.clone(),
-                status: availability_info.0,
-                duration: availability_info.1,
-                open: availability_info.2,
-            });
+#[derive(Serialize)]
+struct Diagnostics {
+    slowest_resources: Vec<ResourceTiming>,
+    clock_skew_secs: Option<i64>,
+    failed_resources: Vec<ResourceFailure>,
+}
+
+#[derive(Serialize)]
+struct ParsedEvent {
+    room: String,
+    start: i64,
+    end: i64,
+    summary: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SkippedEvent {
+    uid: Option<String>,
+    code: &'static str,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct ResourceEvents {
+    resource: i32,
+    fetched_at: i64,
+    events: Vec<ParsedEvent>,
+    skipped: Vec<SkippedEvent>,
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    #[serde(default)]
+    pretty: bool,
+}
+
+#[derive(Deserialize)]
+struct RoomsQuery {
+    horizon: Option<i64>,
+    building: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BuildingQuery {
+    building: Option<String>,
+}
+
+struct RoomFilter {
+    buildings: Vec<String>,
+}
+
+impl RoomFilter {
+    fn parse(building: Option<&str>) -> Result<Self, AppError> {
+        let value = match building {
+            Some(value) => value,
+            None => return Ok(RoomFilter::default()),
+        };
+
+        let buildings: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|building| !building.is_empty())
+            .map(str::to_ascii_uppercase)
+            .collect();
+        if buildings.is_empty() {
+            return Err(AppError::InvalidQuery("building must list at least one building, e.g. V-A or V-A,V-C".to_string()));
+        }
+        for building in &buildings {
+            if !is_building(building) {
+                return Err(AppError::InvalidQuery(format!("invalid building {:?}, expected a campus and block like V-A", building)));
+            }
         }
+        Ok(RoomFilter { buildings })
     }
 
-    room_availabilities.sort_by(|a, b| a.name.cmp(&b.name));
-    let rooms_json = serde_json::to_string(&room_availabilities)?;
-    Ok(HttpResponse::Ok().content_type("application/json").body(rooms_json))
+    // Like the former `^V-[AB]\s?\d*$` pattern: the building as written in the feed, then an optional
+    // space and the room number, so `V-A 12`, `V-A12` and a bare `V-A` all match.
+    fn matches(&self, name: &str) -> bool {
+        self.buildings.iter().any(|building| {
+            let rest = match name.strip_prefix(building.as_str()) {
+                Some(rest) => rest,
+                None => return false,
+            };
+            match rest.strip_prefix(char::is_whitespace) {
+                Some(number) => !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()),
+                None => rest.chars().all(|c| c.is_ascii_digit()),
+            }
+        })
+    }
 }
 
-fn calculate_room_availability(room: &Room, current_timestamp: i64) -> Result<(String, i64, bool), AppError> {
-    let today_8am = Utc::now()
-        .naive_utc()
-        .date()
-        .and_hms_opt(8, 0, 0)
-        .ok_or_else(|| AppError::ParseError)?
-        .timestamp();
-    let tomorrow_8am = today_8am + 86400;
-    let mut open = false;
-    for &(start, end) in &room.availability {
-        if start >= today_8am && end <= tomorrow_8am {
-            open = true;
+impl Default for RoomFilter {
+    fn default() -> Self {
+        RoomFilter { buildings: DEFAULT_BUILDINGS.iter().map(|building| building.to_string()).collect() }
+    }
+}
+
+#[derive(Deserialize)]
+struct AllQuery {
+    include: Option<String>,
+    mode: Option<String>,
+}
+
+impl AllQuery {
+    fn events(&self) -> Result<bool, AppError> {
+        match self.mode.as_deref() {
+            None | Some("intervals") => Ok(false),
+            Some("events") => Ok(true),
+            Some(other) => Err(AppError::InvalidQuery(format!("unknown mode {:?}, expected intervals or events", other))),
         }
+    }
 
-        if start <= current_timestamp && current_timestamp < end {
-            return Ok(("available".to_string(), end - current_timestamp, open));
-        } else if start > current_timestamp {
-            return Ok(("unavailable".to_string(), start - current_timestamp, open));
+    fn counts(&self) -> Result<bool, AppError> {
+        let mut counts = false;
+        for field in self.include.iter().flat_map(|include| include.split(',')) {
+            match field.trim() {
+                "counts" => counts = true,
+                "" => {}
+                other => return Err(AppError::InvalidQuery(format!("unknown include value {:?}, expected counts", other))),
+            }
         }
+        Ok(counts)
     }
-    Ok(("unavailable".to_string(), -1, false))
 }
 
-async fn process_resource(
-    resource: &i32,
-    rooms: &mut HashMap<String, Room>,
-    start_date: &chrono::NaiveDate,
-    end_date: &chrono::NaiveDate
-) -> Result<(), AppError> {
-    let url = format_resource_url(resource, start_date, end_date);
-    let ics = reqwest::get(&url).await?.text().await?;
-    let calendar = IcalParser::new(ics.as_bytes()).next().ok_or(AppError::ParserError)??;
+#[derive(Deserialize)]
+struct CapacityQuery {
+    interval: Option<i64>,
+    horizon: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct SuggestQuery {
+    min_duration: Option<i64>,
+    horizon: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: i64,
+    to: i64,
+    horizon: Option<i64>,
+}
 
-    for event in calendar.events {
-        process_event(event, rooms)?;
+#[derive(Deserialize)]
+struct LiteQuery {
+    sort: Option<String>,
+}
+
+impl LiteQuery {
+    fn next_free(&self) -> Result<bool, AppError> {
+        match self.sort.as_deref() {
+            None | Some("name") => Ok(false),
+            Some("next_free") => Ok(true),
+            Some(other) => Err(AppError::InvalidQuery(format!("unknown sort {:?}, expected name or next_free", other))),
+        }
     }
+}
 
-    Ok(())
+#[derive(Deserialize)]
+struct FreeQuery {
+    from: Option<String>,
+    to: Option<String>,
+    building: Option<String>,
+    horizon: Option<i64>,
 }
 
-fn format_resource_url(
-    resource: &i32,
-    current_date: &chrono::NaiveDate,
-    two_weeks_date: &chrono::NaiveDate
-) -> String {
-    format!("https://planning.univ-ubs.fr/jsp/custom/modules/plannings/anonymous_cal.jsp?resources={}&projectId=1&calType=ical&firstDate={}&lastDate={}",
-            resource, current_date.format(UBS_DATE_FORMAT), two_weeks_date.format(UBS_DATE_FORMAT))
+impl FreeQuery {
+    fn time(value: Option<&str>, name: &str) -> Result<Option<NaiveTime>, AppError> {
+        value
+            .map(|value| NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| {
+                AppError::InvalidQuery(format!("{} must be a time like 14:00, got {:?}", name, value))
+            }))
+            .transpose()
+    }
 }
 
-fn process_event(
-    event: IcalEvent,
-    rooms: &mut HashMap<String, Room>
-) -> Result<(), AppError> {
-    let property_value = event.properties[4].value.clone().unwrap_or_default();
-    let rooms_names = property_value.split("\\,").collect::<Vec<&str>>();
+#[derive(Deserialize)]
+struct IcsQuery {
+    alarm: Option<i64>,
+    horizon: Option<i64>,
+}
 
-    for room_name in rooms_names {
-        let room = rooms.entry(room_name.to_string()).or_insert_with(|| Room::new(room_name.to_string()));
-        let start = NaiveDateTime::parse_from_str(&event.properties[1].value.clone().ok_or(AppError::ParseError)?, ICAL_DATE_FORMAT)?.timestamp();
-        let end = NaiveDateTime::parse_from_str(&event.properties[2].value.clone().ok_or(AppError::ParseError)?, ICAL_DATE_FORMAT)?.timestamp();
-        room.slots.insert((start, end));
+#[derive(Serialize)]
+struct EmptyRooms {
+    matched: Vec<String>,
+    filtered: Vec<String>,
+    empty_resources: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+struct DiagnosticsQuery {
+    slowest: Option<usize>,
+}
+
+struct ConcurrencyLimiter {
+    limit: usize,
+    in_flight: std::sync::Mutex<HashMap<String, usize>>,
+}
+
+impl ConcurrencyLimiter {
+    fn new(limit: usize) -> Self {
+        ConcurrencyLimiter {
+            limit,
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+        }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    fn acquire(self: &Arc<Self>, client: String) -> Option<ConcurrencyPermit> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        let count = in_flight.entry(client.clone()).or_insert(0);
+        if self.limit > 0 && *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(ConcurrencyPermit { limiter: self.clone(), client })
+    }
+}
+
+struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    client: String,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = in_flight.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.client);
+            }
+        }
+    }
+}
+
+struct Config {
+    bind_addr: SocketAddr,
+    schedule: RefreshSchedule,
+    admin_token: Option<String>,
+    align_weeks: bool,
+    opening_time: NaiveTime,
+    closing_time: NaiveTime,
+    window_hours: Option<i64>,
+    skew_warning_secs: i64,
+    key_by_building: bool,
+    upstream_url: String,
+    secondary_upstream_url: Option<String>,
+    refresh_cooldown_secs: i64,
+    initial_refresh: InitialRefresh,
+    cors_max_age: Option<usize>,
+    upstream_project_id: String,
+    upstream_cal_type: String,
+    max_concurrent_per_client: usize,
+    resources_file: Option<String>,
+    resource_buildings: HashMap<i32, String>,
+    trusted_proxies: Vec<IpAddr>,
+    operational_timezone: Tz,
+    cache_file: String,
+    cache_max_age_secs: i64,
+    health_grace_secs: i64,
+    health_max_failures: usize,
+    http_client: reqwest::Client,
+}
+
+impl Config {
+    fn from_env() -> Result<Self, AppError> {
+        let (opening_time, closing_time) = match env_var("FREE_ROOM_OPERATING_HOURS") {
+            Some(value) => parse_time_range(&value)?,
+            None => (
+                NaiveTime::from_hms_opt(DEFAULT_OPERATING_HOURS.0, 0, 0).ok_or(AppError::ParseError)?,
+                NaiveTime::from_hms_opt(DEFAULT_OPERATING_HOURS.1, 0, 0).ok_or(AppError::ParseError)?,
+            ),
+        };
+
+        let bind_addr = match (env_var("FREE_ROOM_BIND_ADDR"), env_var("PORT")) {
+            (Some(value), _) => value,
+            (None, Some(port)) => format!("127.0.0.1:{}", port),
+            (None, None) => DEFAULT_BIND_ADDR.to_string(),
+        };
+
+        let fetch_timeout = match env_var("FREE_ROOM_FETCH_TIMEOUT_SECS") {
+            Some(value) => value.parse().ok().filter(|&secs| secs > 0).map(time::Duration::from_secs).ok_or_else(|| {
+                AppError::Config(format!("FREE_ROOM_FETCH_TIMEOUT_SECS must be a positive number of seconds, got {:?}", value))
+            })?,
+            None => time::Duration::from_secs(DEFAULT_FETCH_TIMEOUT_SECS),
+        };
+
+        Ok(Config {
+            bind_addr: bind_addr.parse().map_err(|_| {
+                AppError::Config(format!("FREE_ROOM_BIND_ADDR must be an address like 0.0.0.0:8080, got {:?}", bind_addr))
+            })?,
+            schedule: RefreshSchedule::from_env()?,
+            admin_token: env_var("FREE_ROOM_ADMIN_TOKEN"),
+            align_weeks: env_flag("FREE_ROOM_ALIGN_WEEKS")?,
+            opening_time,
+            closing_time,
+            window_hours: match env_var("FREE_ROOM_WINDOW_HOURS") {
+                Some(value) => Some(value.parse().ok().filter(|&hours: &i64| hours > 0 && hours <= MAX_HORIZON_SECS / 3600).ok_or_else(|| {
+                    AppError::Config(format!("FREE_ROOM_WINDOW_HOURS must be a positive number of hours, got {:?}", value))
+                })?),
+                None => None,
+            },
+            skew_warning_secs: match env_var("FREE_ROOM_SKEW_WARNING_SECS") {
+                Some(value) => value.parse().ok().filter(|&secs| secs >= 0).ok_or_else(|| {
+                    AppError::Config(format!("FREE_ROOM_SKEW_WARNING_SECS must be a number of seconds, got {:?}", value))
+                })?,
+                None => DEFAULT_SKEW_WARNING_SECS,
+            },
+            key_by_building: match env_var("FREE_ROOM_ROOM_KEY").as_deref() {
+                None | Some("building") => true,
+                Some("name") => false,
+                Some(value) => return Err(AppError::Config(format!("FREE_ROOM_ROOM_KEY must be building or name, got {:?}", value))),
+            },
+            upstream_url: env_var("FREE_ROOM_UPSTREAM_URL").unwrap_or_else(|| UBS_CALENDAR_URL.to_string()),
+            secondary_upstream_url: env_var("FREE_ROOM_SECONDARY_UPSTREAM_URL"),
+            refresh_cooldown_secs: match env_var("FREE_ROOM_REFRESH_COOLDOWN_SECS") {
+                Some(value) => value.parse().ok().filter(|&secs| secs >= 0).ok_or_else(|| {
+                    AppError::Config(format!("FREE_ROOM_REFRESH_COOLDOWN_SECS must be a number of seconds, got {:?}", value))
+                })?,
+                None => DEFAULT_REFRESH_COOLDOWN_SECS,
+            },
+            initial_refresh: InitialRefresh::from_env()?,
+            cors_max_age: match env_var("FREE_ROOM_CORS_MAX_AGE_SECS") {
+                Some(value) => value.parse().map(|secs| Some(secs).filter(|&secs| secs > 0)).map_err(|_| {
+                    AppError::Config(format!("FREE_ROOM_CORS_MAX_AGE_SECS must be a number of seconds, got {:?}", value))
+                })?,
+                None => Some(DEFAULT_CORS_MAX_AGE_SECS),
+            },
+            upstream_project_id: env_var("FREE_ROOM_UPSTREAM_PROJECT_ID").unwrap_or_else(|| "1".to_string()),
+            upstream_cal_type: env_var("FREE_ROOM_UPSTREAM_CAL_TYPE").unwrap_or_else(|| "ical".to_string()),
+            max_concurrent_per_client: match env_var("FREE_ROOM_MAX_CONCURRENT_PER_CLIENT") {
+                Some(value) => value.parse().map_err(|_| {
+                    AppError::Config(format!("FREE_ROOM_MAX_CONCURRENT_PER_CLIENT must be a number of requests, got {:?}", value))
+                })?,
+                None => DEFAULT_MAX_CONCURRENT_PER_CLIENT,
+            },
+            resources_file: env_var("FREE_ROOM_RESOURCES_FILE"),
+            resource_buildings: HashMap::new(),
+            trusted_proxies: match env_var("FREE_ROOM_TRUSTED_PROXIES") {
+                Some(value) => value
+                    .split(',')
+                    .map(|proxy| proxy.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| AppError::Config(format!("FREE_ROOM_TRUSTED_PROXIES must be a comma list of IP addresses, got {:?}", value)))?,
+                None => Vec::new(),
+            },
+            operational_timezone: match env_var("FREE_ROOM_TIMEZONE") {
+                Some(value) => value.parse().map_err(|_| {
+                    AppError::Config(format!("FREE_ROOM_TIMEZONE must be an IANA timezone name, got {:?}", value))
+                })?,
+                None => DEFAULT_TIMEZONE,
+            },
+            cache_file: env_var("FREE_ROOM_CACHE_FILE").unwrap_or_else(|| DEFAULT_CACHE_FILE.to_string()),
+            cache_max_age_secs: match env_var("FREE_ROOM_CACHE_MAX_AGE_SECS") {
+                Some(value) => value.parse().ok().filter(|&secs| secs >= 0).ok_or_else(|| {
+                    AppError::Config(format!("FREE_ROOM_CACHE_MAX_AGE_SECS must be a number of seconds, got {:?}", value))
+                })?,
+                None => DEFAULT_CACHE_MAX_AGE_SECS,
+            },
+            health_grace_secs: match env_var("FREE_ROOM_HEALTH_GRACE_SECS") {
+                Some(value) => value.parse().ok().filter(|&secs| secs >= 0).ok_or_else(|| {
+                    AppError::Config(format!("FREE_ROOM_HEALTH_GRACE_SECS must be a number of seconds, got {:?}", value))
+                })?,
+                None => DEFAULT_HEALTH_GRACE_SECS,
+            },
+            health_max_failures: match env_var("FREE_ROOM_HEALTH_MAX_FAILURES") {
+                Some(value) => value.parse().map_err(|_| {
+                    AppError::Config(format!("FREE_ROOM_HEALTH_MAX_FAILURES must be a number of refreshes, got {:?}", value))
+                })?,
+                None => DEFAULT_HEALTH_MAX_FAILURES,
+            },
+            http_client: reqwest::Client::builder().timeout(fetch_timeout).build()?,
+        })
+    }
+
+    fn horizon(&self, requested: Option<i64>) -> Result<Option<i64>, AppError> {
+        match requested {
+            Some(hours) if hours <= 0 => Err(AppError::InvalidQuery("horizon must be a positive number of hours".to_string())),
+            Some(hours) => hours
+                .checked_mul(3600)
+                .filter(|&secs| secs <= MAX_HORIZON_SECS)
+                .map(Some)
+                .ok_or_else(|| AppError::InvalidQuery(format!("horizon must be at most {} hours", MAX_HORIZON_SECS / 3600))),
+            None => Ok(self.window_hours.map(|hours| hours * 3600)),
+        }
+    }
+
+    fn local_now(&self) -> NaiveDateTime {
+        Utc::now().with_timezone(&self.operational_timezone).naive_local()
+    }
+
+    fn today(&self) -> NaiveDate {
+        self.local_now().date()
+    }
+
+    fn local_date(&self, timestamp: i64) -> NaiveDate {
+        DateTime::from_timestamp(timestamp, 0)
+            .map(|datetime| datetime.with_timezone(&self.operational_timezone).date_naive())
+            .unwrap_or_else(|| self.today())
+    }
+
+    // Wall-clock times repeated when clocks go back resolve to their first occurrence, and times
+    // skipped when clocks go forward resolve to the instant they would have had before the switch.
+    fn local_timestamp(&self, local: NaiveDateTime) -> i64 {
+        let timezone = &self.operational_timezone;
+        match timezone.from_local_datetime(&local).earliest() {
+            Some(datetime) => datetime.timestamp(),
+            None => match timezone.from_local_datetime(&(local + Duration::hours(1))).earliest() {
+                Some(datetime) => datetime.timestamp(),
+                None => local.and_utc().timestamp(),
+            },
+        }
+    }
+
+    fn window_bounds(&self, start_date: NaiveDate, end_date: NaiveDate) -> (i64, i64) {
+        (
+            self.local_timestamp(start_date.and_time(NaiveTime::MIN)),
+            self.local_timestamp(end_date.and_time(NaiveTime::MIN)),
+        )
+    }
+
+    fn next_opening(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let opening = now.date().and_time(self.opening_time);
+        if opening > now {
+            opening
+        } else {
+            opening + Duration::days(1)
+        }
+    }
+
+    fn fetch_window(&self, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let start_date = today - Duration::weeks(START_WEEK_OFFSET);
+        let end_date = start_date + Duration::weeks(END_WEEK_OFFSET);
+        if !self.align_weeks {
+            return (start_date, end_date);
+        }
+
+        // The end date is exclusive, so the window runs up to the following Monday to keep its last Sunday.
+        let monday = start_date - Duration::days(start_date.weekday().num_days_from_monday() as i64);
+        let next_monday = end_date + Duration::days((7 - end_date.weekday().num_days_from_monday() as i64) % 7);
+        (monday, next_monday)
+    }
+
+    // Forwarding headers are only believed when the connection comes from a trusted proxy,
+    // otherwise any client could pick a new identity on every request.
+    fn client_id(&self, peer: Option<IpAddr>, forwarded: Option<&str>) -> String {
+        match peer {
+            Some(peer) if self.trusted_proxies.contains(&peer) => forwarded.map(str::to_string).unwrap_or_else(|| peer.to_string()),
+            Some(peer) => peer.to_string(),
+            None => "unknown".to_string(),
+        }
+    }
+
+    fn require_admin(&self, req: &HttpRequest) -> Result<(), AppError> {
+        let token = self.admin_token.as_deref().ok_or(AppError::AdminDisabled)?;
+        let provided = req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if provided == Some(token) {
+            Ok(())
+        } else {
+            Err(AppError::Unauthorized)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum InitialRefresh {
+    Await,
+    Background,
+    Delayed(u64),
+}
+
+impl InitialRefresh {
+    fn from_env() -> Result<Self, AppError> {
+        match env_var("FREE_ROOM_INITIAL_REFRESH").as_deref() {
+            None | Some("background") => Ok(InitialRefresh::Background),
+            Some("await") => Ok(InitialRefresh::Await),
+            Some("delayed") => match env_var("FREE_ROOM_INITIAL_REFRESH_DELAY_SECS") {
+                Some(value) => value.parse().map(InitialRefresh::Delayed).map_err(|_| {
+                    AppError::Config(format!("FREE_ROOM_INITIAL_REFRESH_DELAY_SECS must be a number of seconds, got {:?}", value))
+                }),
+                None => Ok(InitialRefresh::Delayed(DEFAULT_INITIAL_REFRESH_DELAY_SECS)),
+            },
+            Some(value) => Err(AppError::Config(format!("FREE_ROOM_INITIAL_REFRESH must be await, background or delayed, got {:?}", value))),
+        }
+    }
+}
+
+struct RefreshSchedule {
+    interval: u64,
+    minutes: Vec<u32>,
+    quiet_hours: Option<(u32, u32)>,
+    quiet_interval: u64,
+}
+
+impl RefreshSchedule {
+    fn from_env() -> Result<Self, AppError> {
+        let interval = match env_var("FREE_ROOM_REFRESH_SECS") {
+            Some(value) => value.parse().ok().filter(|&secs| secs > 0).ok_or_else(|| {
+                AppError::Config(format!("FREE_ROOM_REFRESH_SECS must be a positive number of seconds, got {:?}", value))
+            })?,
+            None => DEFAULT_REFRESH_SECS,
+        };
+        let minutes = match env_var("FREE_ROOM_REFRESH_MINUTES") {
+            Some(value) => parse_minutes(&value)?,
+            None => Vec::new(),
+        };
+        let quiet_hours = match env_var("FREE_ROOM_QUIET_HOURS") {
+            Some(value) => Some(parse_hour_range(&value)?),
+            None => None,
+        };
+        let quiet_interval = match env_var("FREE_ROOM_QUIET_INTERVAL_SECS") {
+            Some(value) => value.parse().ok().filter(|&secs| secs > 0).ok_or_else(|| {
+                AppError::Config(format!("FREE_ROOM_QUIET_INTERVAL_SECS must be a positive number of seconds, got {:?}", value))
+            })?,
+            None => DEFAULT_QUIET_INTERVAL_SECS,
+        };
+
+        Ok(RefreshSchedule {
+            interval,
+            minutes,
+            quiet_hours,
+            quiet_interval,
+        })
+    }
+
+    fn longest_gap(&self) -> u64 {
+        match self.quiet_hours {
+            Some(_) => self.interval.max(self.quiet_interval),
+            None => self.interval,
+        }
+    }
+
+    fn is_quiet(&self, hour: u32) -> bool {
+        match self.quiet_hours {
+            Some((start, end)) if start <= end => hour >= start && hour < end,
+            Some((start, end)) => hour >= start || hour < end,
+            None => false,
+        }
+    }
+
+    fn next_refresh(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let hour_start = now.date().and_hms_opt(now.hour(), 0, 0).unwrap_or(now);
+
+        if let Some((_, end)) = self.quiet_hours.filter(|_| self.is_quiet(now.hour())) {
+            let mut quiet_end = now.date().and_hms_opt(end, 0, 0).unwrap_or(now);
+            if quiet_end <= now {
+                quiet_end += Duration::days(1);
+            }
+            return quiet_end.min(now + Duration::seconds(self.quiet_interval as i64));
+        }
+
+        for hour in 0..2 {
+            for &minute in &self.minutes {
+                let candidate = hour_start + Duration::hours(hour) + Duration::minutes(minute as i64);
+                if candidate > now {
+                    return candidate;
+                }
+            }
+        }
+
+        now + Duration::seconds(self.interval as i64)
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn env_flag(name: &str) -> Result<bool, AppError> {
+    match env_var(name).as_deref().map(str::trim) {
+        None | Some("0") | Some("false") => Ok(false),
+        Some("1") | Some("true") => Ok(true),
+        Some(value) => Err(AppError::Config(format!("{} must be true or false, got {:?}", name, value))),
+    }
+}
+
+fn parse_minutes(value: &str) -> Result<Vec<u32>, AppError> {
+    let mut minutes = value
+        .split(',')
+        .map(|minute| minute.trim().parse::<u32>().ok().filter(|&m| m < 60))
+        .collect::<Option<Vec<u32>>>()
+        .ok_or_else(|| AppError::Config(format!("FREE_ROOM_REFRESH_MINUTES must be a comma list of minutes (0-59), got {:?}", value)))?;
+    minutes.sort_unstable();
+    minutes.dedup();
+    Ok(minutes)
+}
+
+fn parse_time_range(value: &str) -> Result<(NaiveTime, NaiveTime), AppError> {
+    let invalid = || AppError::Config(format!("FREE_ROOM_OPERATING_HOURS must look like \"08:00-20:00\", got {:?}", value));
+    let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+    let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| invalid())?;
+    let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| invalid())?;
+    if start >= end {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+fn parse_hour_range(value: &str) -> Result<(u32, u32), AppError> {
+    let invalid = || AppError::Config(format!("FREE_ROOM_QUIET_HOURS must look like \"20-7\" (hours 0-23), got {:?}", value));
+    let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+    let start = start.trim().parse::<u32>().ok().filter(|&h| h < 24).ok_or_else(invalid)?;
+    let end = end.trim().parse::<u32>().ok().filter(|&h| h < 24).ok_or_else(invalid)?;
+    Ok((start, end))
+}
+
+fn load_resources(path: Option<&str>) -> Result<Vec<(i32, Option<String>)>, AppError> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(RESOURCES.iter().map(|&resource| (resource, None)).collect()),
+    };
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| AppError::Config(format!("FREE_ROOM_RESOURCES_FILE {:?} can't be read: {}", path, e)))?;
+    parse_resources(&content).map_err(|reason| AppError::Config(format!("FREE_ROOM_RESOURCES_FILE {:?} {}", path, reason)))
+}
+
+fn parse_resources(content: &str) -> Result<Vec<(i32, Option<String>)>, String> {
+    let content = content.trim();
+    let resources: Vec<(i32, Option<String>)> = if content.starts_with('[') {
+        serde_json::from_str::<Vec<ResourceEntry>>(content)
+            .map_err(|e| format!("is not a JSON list of ids: {}", e))?
+            .into_iter()
+            .map(|entry| match entry {
+                ResourceEntry::Id(resource) => (resource, None),
+                ResourceEntry::WithBuilding { id, building } => (id, Some(building)),
+            })
+            .collect()
+    } else {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (resource, building) = match line.split_once(char::is_whitespace) {
+                    Some((resource, building)) => (resource, Some(building.trim().to_string())),
+                    None => (line, None),
+                };
+                resource.parse().map(|resource| (resource, building)).map_err(|_| format!("has an invalid resource id {:?}", line))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    if resources.is_empty() {
+        return Err("contains no resource id".to_string());
+    }
+    if let Some((resource, _)) = resources.iter().find(|&&(resource, _)| resource <= 0) {
+        return Err(format!("has an invalid resource id {}", resource));
+    }
+    if let Some(building) = resources.iter().filter_map(|(_, building)| building.as_deref()).find(|building| !is_building(building)) {
+        return Err(format!("has an invalid building {:?}, expected a campus and block like V-A", building));
+    }
+    Ok(resources
+        .into_iter()
+        .map(|(resource, building)| (resource, building.map(|building| building.to_ascii_uppercase())))
+        .collect())
+}
+
+// Rewrites the resources file in the format it was written in, one id per line unless it held a JSON array.
+fn save_resources(config: &Config, resources: &[i32]) -> Result<(), AppError> {
+    let path = match &config.resources_file {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let json = std::fs::read_to_string(path).is_ok_and(|content| content.trim_start().starts_with('['));
+    let temporary = format!("{}.tmp", path);
+    std::fs::write(&temporary, format_resources(resources, &config.resource_buildings, json)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+fn format_resources(resources: &[i32], buildings: &HashMap<i32, String>, json: bool) -> Result<String, AppError> {
+    if json {
+        let entries: Vec<ResourceEntry> = resources
+            .iter()
+            .map(|&resource| match buildings.get(&resource) {
+                Some(building) => ResourceEntry::WithBuilding { id: resource, building: building.clone() },
+                None => ResourceEntry::Id(resource),
+            })
+            .collect();
+        return Ok(serde_json::to_string(&entries)? + "\n");
+    }
+    Ok(resources
+        .iter()
+        .map(|resource| match buildings.get(resource) {
+            Some(building) => format!("{} {}\n", resource, building),
+            None => format!("{}\n", resource),
+        })
+        .collect())
+}
+
+fn load_cache(config: &Config) -> HashMap<RoomKey, Room> {
+    let mut rooms = HashMap::new();
+    let content = match std::fs::read_to_string(&config.cache_file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return rooms,
+        Err(e) => {
+            eprintln!("Room cache {} can't be read, starting empty: {}", config.cache_file, e);
+            return rooms;
+        }
+    };
+    let cache: RoomCache = match serde_json::from_str(&content) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Room cache {} is corrupt, starting empty: {}", config.cache_file, e);
+            return rooms;
+        }
+    };
+
+    let now = Utc::now().timestamp();
+    if now - cache.saved_at > config.cache_max_age_secs {
+        eprintln!(
+            "Room cache {} was saved {} seconds ago, more than the {} seconds allowed, starting empty",
+            config.cache_file, now - cache.saved_at, config.cache_max_age_secs,
+        );
+        return rooms;
+    }
+    // Rooms are kept even when all their bookings are over, so rooms known to be empty survive a restart.
+    for cached in cache.rooms {
+        let key = RoomKey::new(&cached.name, config.key_by_building, None);
+        let mut room = Room::new(key.display_name(), key.building.clone());
+        room.slots.extend(cached.slots.into_iter().filter(|&(_, end)| end > now));
+        room.resources.extend(cached.resources);
+        room.window_end = cached.window_end;
+        rooms.insert(key, room);
+    }
+    eprintln!("Loaded {} rooms from cache {} saved at {}", rooms.len(), config.cache_file, cache.saved_at);
+    rooms
+}
+
+fn save_cache(path: &str, cache: &RoomCache) -> Result<(), AppError> {
+    let temporary = format!("{}.tmp", path);
+    std::fs::write(&temporary, serde_json::to_string(cache)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), AppError> {
+    let mut config = Config::from_env()?;
+    let entries = load_resources(config.resources_file.as_deref())?;
+    config.resource_buildings = entries
+        .iter()
+        .filter_map(|(resource, building)| building.clone().map(|building| (*resource, building)))
+        .collect();
+    let config = Arc::new(config);
+    let rooms = Arc::new(Mutex::new(load_cache(&config)));
+    let status = Arc::new(Mutex::new(CrawlStatus::default()));
+    let resources = Arc::new(Mutex::new(entries.into_iter().map(|(resource, _)| resource).collect::<Vec<_>>()));
+    let rooms_clone = rooms.clone();
+    let resources_clone = resources.clone();
+    let status_clone = status.clone();
+    let config_clone = config.clone();
+
+    if let InitialRefresh::Await = config.initial_refresh {
+        update_rooms(&config, &resources, &rooms, &status).await;
+    }
+
+    tokio::spawn(async move {
+        match config_clone.initial_refresh {
+            InitialRefresh::Await => {}
+            InitialRefresh::Background => {
+                update_rooms(&config_clone, &resources_clone, &rooms_clone, &status_clone).await;
+            }
+            InitialRefresh::Delayed(secs) => {
+                time::sleep(time::Duration::from_secs(secs)).await;
+                update_rooms(&config_clone, &resources_clone, &rooms_clone, &status_clone).await;
+            }
+        }
+
+        loop {
+            let now = config_clone.local_now();
+            let delay = (config_clone.schedule.next_refresh(now) - now).to_std().unwrap_or_default();
+            time::sleep(delay).await;
+            update_rooms(&config_clone, &resources_clone, &rooms_clone, &status_clone).await;
+        }
+    });
+
+    let bind_addr = config.bind_addr;
+    let limiter = Arc::new(ConcurrencyLimiter::new(config.max_concurrent_per_client));
+
+    HttpServer::new(move || {
+        let limiter = limiter.clone();
+        let client_config = config.clone();
+        App::new()
+            .wrap_fn(move |req, srv| {
+                let client = client_config.client_id(req.peer_addr().map(|addr| addr.ip()), req.connection_info().realip_remote_addr());
+                let permit = limiter.acquire(client);
+                let call = match permit {
+                    Some(_) => Ok(srv.call(req)),
+                    None => Err(req),
+                };
+                async move {
+                    match call {
+                        Ok(response) => {
+                            let response = response.await?;
+                            drop(permit);
+                            Ok(response.map_into_left_body())
+                        }
+                        Err(req) => Ok(req.into_response(AppError::TooManyRequests.error_response()).map_into_right_body()),
+                    }
+                }
+            })
+            .wrap(
+                Cors::permissive().max_age(config.cors_max_age)
+            )
+            .wrap(middleware::Compress::default())
+            .app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(rooms.clone()))
+            .app_data(web::Data::new(status.clone()))
+            .app_data(web::Data::new(resources.clone()))
+            .service(ping)
+            .service(get_health)
+            .service(get_all_rooms_info)
+            .service(get_rooms_availability_diff)
+            .service(get_rooms_availability)
+            .service(get_diagnostics)
+            .service(get_empty_rooms)
+            .service(get_resource_events)
+            .service(get_metrics)
+            .service(get_export)
+            .service(get_next_opening)
+            .service(get_room)
+            .service(get_room_ics)
+            .service(get_occupancy)
+            .service(get_free_rooms)
+            .service(get_capacity)
+            .service(get_resources)
+            .service(add_resource)
+            .service(remove_resource)
+            .service(refresh)
+            .service(get_building_suggestions)
+    })
+        .bind(bind_addr)?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+async fn update_rooms(
+    config: &Config,
+    resources: &Arc<Mutex<Vec<i32>>>,
+    rooms: &Arc<Mutex<HashMap<RoomKey, Room>>>,
+    status: &Arc<Mutex<CrawlStatus>>
+) -> Option<usize> {
+    {
+        let mut status = status.lock().await;
+        if status.refreshing {
+            eprintln!("A refresh is already running, skipping this one");
+            return None;
+        }
+        status.refreshing = true;
+        status.last_refresh_started = Some(Utc::now().timestamp());
+    }
+    let _guard = RefreshGuard { status: status.clone() };
+    let (start_date, end_date) = config.fetch_window(config.today());
+    let resources = resources.lock().await.clone();
+    let window = config.window_bounds(start_date, end_date);
+
+    // Fetch without holding the rooms lock, then swap the rebuilt rooms in one go.
+    let results: Vec<(i32, Result<Vec<ParsedEvent>, AppError>)> = stream::iter(resources)
+        .map(|resource| async move {
+            (resource, process_resource(config, &resource, status, &start_date, &end_date).await)
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    // Rooms are rebuilt from this cycle's results so cancelled or moved bookings disappear, only
+    // rooms a failed resource contributed to keep their previous bookings.
+    let mut fresh = HashMap::new();
+    let mut succeeded = Vec::new();
+    let mut failures = Vec::new();
+    for (resource, result) in results {
+        match result {
+            Ok(events) => {
+                let building = config.resource_buildings.get(&resource).map(String::as_str);
+                merge_events(&mut fresh, resource, config.key_by_building, building, window.1, events);
+                succeeded.push(resource);
+            }
+            Err(e) => {
+                eprintln!("Error processing resource {}: {}", resource, e);
+                failures.push(ResourceFailure {
+                    resource,
+                    code: e.code(),
+                    message: e.to_string(),
+                    failed_at: Utc::now().timestamp(),
+                });
+            }
+        }
+    }
+
+    // Only rooms of failed resources, or missing from this cycle, are copied out of the shared map,
+    // and the snapshot for the cache is taken from the rebuilt rooms, so readers are blocked for the
+    // swap alone.
+    let failed: HashSet<i32> = failures.iter().map(|failure| failure.resource).collect();
+    let crawled: HashSet<i32> = succeeded.iter().cloned().chain(failed.iter().cloned()).collect();
+    let previous: HashMap<RoomKey, Room> = rooms
+        .lock()
+        .await
+        .iter()
+        .filter(|(key, room)| !room.resources.is_disjoint(&failed) || !fresh.contains_key(*key))
+        .map(|(key, room)| (key.clone(), room.clone()))
+        .collect();
+    carry_over_rooms(&mut fresh, previous, &failed, &crawled, window);
+    let count = fresh.len();
+    let cache = (!succeeded.is_empty()).then(|| RoomCache {
+        saved_at: Utc::now().timestamp(),
+        rooms: fresh
+            .values()
+            .map(|room| CachedRoom {
+                name: room.name.clone(),
+                resources: room.resources.iter().cloned().collect(),
+                window_end: room.window_end,
+                slots: room.sorted_slots(),
+            })
+            .collect(),
+    });
+    *rooms.lock().await = fresh;
+
+    let now = Utc::now().timestamp();
+    let mut status = status.lock().await;
+    let errors = failures.len();
+    for &resource in &succeeded {
+        status.last_successes.insert(resource, now);
+        status.resource_failures.remove(&resource);
+    }
+    for failure in failures {
+        status.resource_failures.insert(failure.resource, failure);
+    }
+
+    status.last_refresh = Some(Utc::now().timestamp());
+    status.window = Some((start_date, end_date));
+    status.last_refresh_errors = errors;
+    if !succeeded.is_empty() {
+        status.last_successful_refresh = status.last_refresh;
+        status.failed_refreshes = 0;
+    } else if errors > 0 {
+        status.failed_refreshes += 1;
+    }
+    if let Some(skew) = status.clock_skew().filter(|skew| skew.abs() > config.skew_warning_secs) {
+        eprintln!("Feed clock differs from server clock by {} seconds", skew);
+    }
+    drop(status);
+
+    if let Some(cache) = cache {
+        if let Err(e) = save_cache(&config.cache_file, &cache) {
+            eprintln!("Failed to save room cache {}: {}", config.cache_file, e);
+        }
+    }
+    Some(count)
+}
+
+#[post("/refresh")]
+async fn refresh(
+    config: web::Data<Arc<Config>>,
+    resources: web::Data<Arc<Mutex<Vec<i32>>>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    status.lock().await.try_start_refresh(Utc::now().timestamp(), config.refresh_cooldown_secs)?;
+    let started = Instant::now();
+    // The crawl runs on its own task, so a client disconnecting mid-refresh doesn't cancel it.
+    let crawl = tokio::spawn({
+        let (config, resources, data, status) = (Arc::clone(&config), Arc::clone(&resources), Arc::clone(&data), Arc::clone(&status));
+        async move { update_rooms(&config, &resources, &data, &status).await }
+    });
+    let rooms = crawl.await.map_err(|_| AppError::RefreshFailed)?.ok_or(AppError::RefreshInProgress)?;
+
+    let result = RefreshResult {
+        refreshed_at: Utc::now().timestamp(),
+        rooms,
+        duration_ms: started.elapsed().as_millis() as u64,
+    };
+    let result_json = to_json(&result, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(result_json))
+}
+
+#[get("/ping")]
+async fn ping() -> HttpResponse {
+    HttpResponse::Ok().body("pong")
+}
+
+#[get("/health")]
+async fn get_health(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let rooms = data.lock().await.len();
+    let status = status.lock().await;
+    let health = Health {
+        ready: status.healthy(Utc::now().timestamp(), config.health_grace_secs, config.health_max_failures),
+        rooms,
+        last_success: status.last_successful_refresh,
+        errors: status.last_refresh_errors,
+        failed_refreshes: status.failed_refreshes,
+    };
+
+    let health_json = to_json(&health, format.pretty)?;
+    let mut response = if health.ready { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+    Ok(response.content_type("application/json").body(health_json))
+}
+
+#[get("/all")]
+async fn get_all_rooms_info(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    query: web::Query<RoomsQuery>,
+    all: web::Query<AllQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let horizon = config.horizon(query.horizon)?;
+    let counts = all.counts()?;
+    let events = all.events()?;
+    let generated_at = Utc::now().timestamp();
+    let last_refresh = status.lock().await.last_successful_refresh;
+    let mut rooms = HashMap::new();
+    let filter = RoomFilter::parse(query.building.as_deref())?;
+    for room in data.lock().await.values() {
+        let current_timestamp = Utc::now().timestamp();
+        let mut availability = room.availability(current_timestamp);
+        if filter.matches(&room.name) && !availability.is_empty() {
+            if let Some(horizon) = horizon {
+                clip_availability(&mut availability, current_timestamp + horizon);
+            }
+            let windows = if events {
+                RoomWindows::Events {
+                    reference: current_timestamp,
+                    events: room.availability_events(current_timestamp, horizon.map(|horizon| current_timestamp + horizon)),
+                    upcoming_slots: Some(room.upcoming_slots(current_timestamp)).filter(|_| counts),
+                }
+            } else if counts {
+                RoomWindows::WithCounts {
+                    availability,
+                    upcoming_slots: room.upcoming_slots(current_timestamp),
+                }
+            } else {
+                RoomWindows::Windows(availability)
+            };
+            rooms.insert(room.name.clone(), windows);
+        }
+    }
+    let response = RoomsResponse { generated_at, last_refresh, rooms };
+    let rooms_json = to_json(&response, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(rooms_json))
+}
+
+#[get("/export")]
+async fn get_export(
+    req: HttpRequest,
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    config.require_admin(&req)?;
+    let generated_at = Utc::now().timestamp();
+    let refreshed_at = status.lock().await.last_successful_refresh;
+    let mut rooms = Vec::new();
+    for room in data.lock().await.values() {
+        let mut resources: Vec<_> = room.resources.iter().cloned().collect();
+        resources.sort();
+        rooms.push(ExportRoom {
+            name: room.name.clone(),
+            building: room.building.clone(),
+            resources,
+            slots: room.sorted_slots(),
+            availability: room.availability(generated_at),
+        });
+    }
+    rooms.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let export = Export { generated_at, refreshed_at, rooms };
+    let export_json = to_json(&export, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(export_json))
+}
+
+#[get("/diagnostics")]
+async fn get_diagnostics(
+    req: HttpRequest,
+    config: web::Data<Arc<Config>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    query: web::Query<DiagnosticsQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    config.require_admin(&req)?;
+    let slowest = query.slowest.unwrap_or(DEFAULT_SLOWEST_RESOURCES);
+    let status = status.lock().await;
+    let mut failed_resources: Vec<_> = status.resource_failures.values().cloned().collect();
+    failed_resources.sort_by_key(|failure| failure.resource);
+    let diagnostics = Diagnostics {
+        slowest_resources: status.slowest_resources(slowest),
+        clock_skew_secs: status.clock_skew(),
+        failed_resources,
+    };
+    let diagnostics_json = to_json(&diagnostics, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(diagnostics_json))
+}
+
+#[get("/diagnostics/empty-rooms")]
+async fn get_empty_rooms(
+    req: HttpRequest,
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    config.require_admin(&req)?;
+    let (window, mut empty_resources) = {
+        let status = status.lock().await;
+        let window = status.window.map(|(start_date, end_date)| config.window_bounds(start_date, end_date));
+        let empty_resources: Vec<i32> = status.resource_timings
+            .values()
+            .filter(|timing| timing.events == 0 && !status.resource_failures.contains_key(&timing.resource))
+            .map(|timing| timing.resource)
+            .collect();
+        (window, empty_resources)
+    };
+
+    let filter = RoomFilter::default();
+    let mut empty_rooms = EmptyRooms { matched: Vec::new(), filtered: Vec::new(), empty_resources: Vec::new() };
+    for room in data.lock().await.values() {
+        let booked = match window {
+            Some((start, end)) => room.slots.iter().any(|&(slot_start, slot_end)| slot_start < end && slot_end > start),
+            None => !room.slots.is_empty(),
+        };
+        if !booked {
+            if filter.matches(&room.name) {
+                empty_rooms.matched.push(room.name.clone());
+            } else {
+                empty_rooms.filtered.push(room.name.clone());
+            }
+        }
+    }
+
+    empty_rooms.matched.sort();
+    empty_rooms.filtered.sort();
+    empty_resources.sort();
+    empty_rooms.empty_resources = empty_resources;
+    let empty_rooms_json = to_json(&empty_rooms, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(empty_rooms_json))
+}
+
+#[get("/diagnostics/resource/{id}/events")]
+async fn get_resource_events(
+    req: HttpRequest,
+    config: web::Data<Arc<Config>>,
+    path: web::Path<i32>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    config.require_admin(&req)?;
+    let resource = path.into_inner();
+    if resource <= 0 {
+        return Err(AppError::InvalidResource(resource));
+    }
+
+    let (start_date, end_date) = config.fetch_window(config.today());
+    let url = format_resource_url(&config, &config.upstream_url, &resource, &start_date, &end_date);
+    let (ics, fetched_at) = fetch_ics(&config.http_client, &url).await?;
+    let window = config.window_bounds(start_date, end_date);
+    let (events, skipped) = parse_ics(&ics, window)?;
+
+    let resource_events = ResourceEvents { resource, fetched_at, events, skipped };
+    let resource_events_json = to_json(&resource_events, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(resource_events_json))
+}
+
+#[get("/metrics")]
+async fn get_metrics(
+    status: web::Data<Arc<Mutex<CrawlStatus>>>
+) -> Result<HttpResponse, AppError> {
+    let status = status.lock().await;
+    let mut metrics = String::from("# HELP free_room_resource_fetch_seconds Fetch and parse duration of each resource during the last refresh.\n# TYPE free_room_resource_fetch_seconds gauge\n");
+    for timing in status.slowest_resources(status.resource_timings.len()) {
+        metrics.push_str(&format!("free_room_resource_fetch_seconds{{resource=\"{}\"}} {:.3}\n", timing.resource, timing.duration_ms as f64 / 1000.0));
+    }
+    if let Some(skew) = status.clock_skew() {
+        metrics.push_str("# HELP free_room_clock_skew_seconds Estimated offset between the feed DTSTAMP and the server clock.\n# TYPE free_room_clock_skew_seconds gauge\n");
+        metrics.push_str(&format!("free_room_clock_skew_seconds {}\n", skew));
+    }
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics))
+}
+
+#[get("/lite/diff")]
+async fn get_rooms_availability_diff(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    query: web::Query<DiffQuery>,
+    building: web::Query<BuildingQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    if query.from >= query.to {
+        return Err(AppError::InvalidQuery("from must be lower than to".to_string()));
+    }
+    // Clipping would turn every room unavailable past the window, so offsets beyond it are rejected instead.
+    if let Some(horizon) = config.horizon(query.horizon)? {
+        if query.to * 3600 > horizon {
+            return Err(AppError::InvalidQuery(format!("to must be within the {} hour window", horizon / 3600)));
+        }
+    }
+
+    let rooms = data.lock().await;
+    let now = Utc::now().timestamp();
+    let from_timestamp = now + query.from * 3600;
+    let to_timestamp = now + query.to * 3600;
+    let mut transitions = Vec::new();
+    let filter = RoomFilter::parse(building.building.as_deref())?;
+
+    for room in rooms.values() {
+        if filter.matches(&room.name) {
+            let from_info = calculate_room_availability(room, &room.availability(from_timestamp), from_timestamp, &config)?;
+            let to_info = calculate_room_availability(room, &room.availability(to_timestamp), to_timestamp, &config)?;
+            if from_info.0 != to_info.0 {
+                transitions.push(RoomTransition {
+                    name: room.name.clone(),
+                    from_status: from_info.0,
+                    to_status: to_info.0,
+                    transition_at: Some(from_timestamp + from_info.1).filter(|_| from_info.1 >= 0),
+                });
+            }
+        }
+    }
+
+    transitions.sort_by(|a, b| a.name.cmp(&b.name));
+    let transitions_json = to_json(&transitions, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(transitions_json))
+}
+
+#[get("/lite/{hour_offset}")]
+async fn get_rooms_availability(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    path: web::Path<i64>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    query: web::Query<RoomsQuery>,
+    lite: web::Query<LiteQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let horizon = config.horizon(query.horizon)?;
+    let rooms = data.lock().await;
+    let status = status.lock().await;
+    let fresh_secs = 2 * config.schedule.longest_gap() as i64;
+    let offset = path.into_inner() * 3600;
+    let current_timestamp = Utc::now().timestamp() + offset;
+    let mut room_availabilities = Vec::new();
+    let filter = RoomFilter::parse(query.building.as_deref())?;
+
+    for room in rooms.values() {
+        if filter.matches(&room.name) {
+            let mut availability = room.availability(current_timestamp);
+            if let Some(horizon) = horizon {
+                clip_availability(&mut availability, current_timestamp + horizon);
+            }
+            let availability_info = calculate_room_availability(room, &availability, current_timestamp, &config)?;
+            room_availabilities.push(RoomAvailability {
+                name: room.name.clone(),
+                status: availability_info.0,
+                duration: availability_info.1,
+                open: availability_info.2,
+                busy_since: availability_info.3,
+                confidence: status.confidence(room, Utc::now().timestamp(), fresh_secs),
+            });
+        }
+    }
+
+    room_availabilities.sort_by(|a, b| a.name.cmp(&b.name));
+    if lite.next_free()? {
+        room_availabilities.sort_by_key(|room| room.next_free_in());
+    }
+    let response = RoomsResponse {
+        generated_at: Utc::now().timestamp(),
+        last_refresh: status.last_successful_refresh,
+        rooms: room_availabilities,
+    };
+    let rooms_json = to_json(&response, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(rooms_json))
+}
+
+#[get("/room/{name}")]
+async fn get_room(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    path: web::Path<String>,
+    query: web::Query<RoomsQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let name = path.into_inner();
+    let horizon = config.horizon(query.horizon)?;
+    let rooms = data.lock().await;
+    let status = status.lock().await;
+    let fresh_secs = 2 * config.schedule.longest_gap() as i64;
+    let current_timestamp = Utc::now().timestamp();
+    let filter = RoomFilter::parse(query.building.as_deref())?;
+
+    let room = rooms
+        .values()
+        .find(|room| room.name.eq_ignore_ascii_case(&name) && filter.matches(&room.name))
+        .ok_or_else(|| AppError::UnknownRoom(name.clone()))?;
+    let mut availability = room.availability(current_timestamp);
+    if let Some(horizon) = horizon {
+        clip_availability(&mut availability, current_timestamp + horizon);
+    }
+    let availability_info = calculate_room_availability(room, &availability, current_timestamp, &config)?;
+
+    let room_detail = RoomDetail {
+        availability_info: RoomAvailability {
+            name: room.name.clone(),
+            status: availability_info.0,
+            duration: availability_info.1,
+            open: availability_info.2,
+            busy_since: availability_info.3,
+            confidence: status.confidence(room, current_timestamp, fresh_secs),
+        },
+        availability,
+    };
+    let room_detail_json = to_json(&room_detail, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(room_detail_json))
+}
+
+#[get("/ics/{name}")]
+async fn get_room_ics(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    path: web::Path<String>,
+    query: web::Query<IcsQuery>,
+) -> Result<HttpResponse, AppError> {
+    let name = path.into_inner();
+    if query.alarm.is_some_and(|minutes| minutes < 0) {
+        return Err(AppError::InvalidQuery("alarm must be a non-negative number of minutes".to_string()));
+    }
+
+    let rooms = data.lock().await;
+    let room = rooms
+        .values()
+        .find(|room| room.name.eq_ignore_ascii_case(&name))
+        .ok_or_else(|| AppError::UnknownRoom(name.clone()))?;
+    let current_timestamp = Utc::now().timestamp();
+    let mut availability = room.availability(current_timestamp);
+    if let Some(horizon) = config.horizon(query.horizon)? {
+        clip_availability(&mut availability, current_timestamp + horizon);
+    }
+
+    let calendar = format_free_windows_ics(room, &availability, current_timestamp, query.alarm)?;
+    Ok(HttpResponse::Ok().content_type("text/calendar; charset=utf-8").body(calendar))
+}
+
+#[get("/buildings/{building}/suggest")]
+async fn get_building_suggestions(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    path: web::Path<String>,
+    query: web::Query<SuggestQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let building = path.into_inner();
+    let min_duration = query.min_duration.unwrap_or(0);
+    if min_duration < 0 {
+        return Err(AppError::InvalidQuery("min_duration must be a non-negative number of seconds".to_string()));
+    }
+
+    let filter = RoomFilter::parse(Some(&building))?;
+    let horizon = config.horizon(query.horizon)?;
+    let current_timestamp = Utc::now().timestamp();
+    let rooms = data.lock().await;
+    let mut suggestions = Vec::new();
+    for room in rooms.values() {
+        if filter.matches(&room.name) {
+            let mut availability = room.availability(current_timestamp);
+            if let Some(horizon) = horizon {
+                clip_availability(&mut availability, current_timestamp + horizon);
+            }
+            if let Some((available_at, duration)) = next_window(&availability, current_timestamp, min_duration) {
+                suggestions.push((room.room_number(), RoomSuggestion {
+                    name: room.name.clone(),
+                    status: if available_at <= current_timestamp { "available" } else { "unavailable" }.to_string(),
+                    available_at,
+                    duration,
+                }));
+            }
+        }
+    }
+
+    if suggestions.iter().any(|(_, suggestion)| suggestion.status == "available") {
+        suggestions.retain(|(_, suggestion)| suggestion.status == "available");
+    }
+    suggestions.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.name.cmp(&b.1.name)));
+    let suggestions: Vec<_> = suggestions.into_iter().map(|(_, suggestion)| suggestion).collect();
+    let suggestions_json = to_json(&suggestions, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(suggestions_json))
+}
+
+#[get("/occupancy")]
+async fn get_occupancy(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    building: web::Query<BuildingQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let days: Vec<NaiveDate> = match status.lock().await.window {
+        Some((start_date, end_date)) => start_date
+            .iter_days()
+            .take_while(|day| *day < end_date)
+            .filter(|day| day.weekday().num_days_from_monday() < 5)
+            .collect(),
+        None => Vec::new(),
+    };
+    let filter = RoomFilter::parse(building.building.as_deref())?;
+    let mut occupancies = Vec::new();
+
+    for room in data.lock().await.values() {
+        if filter.matches(&room.name) && !days.is_empty() {
+            let total: f64 = days
+                .iter()
+                .map(|day| {
+                    let opening = config.local_timestamp(day.and_time(config.opening_time));
+                    let closing = config.local_timestamp(day.and_time(config.closing_time));
+                    room.busy_seconds(opening, closing) as f64 / (closing - opening) as f64
+                })
+                .sum();
+            occupancies.push(RoomOccupancy {
+                name: room.name.clone(),
+                average_occupancy: total / days.len() as f64,
+                days: days.len(),
+            });
+        }
+    }
+
+    occupancies.sort_by(|a, b| a.average_occupancy.total_cmp(&b.average_occupancy).then(a.name.cmp(&b.name)));
+    let occupancies_json = to_json(&occupancies, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(occupancies_json))
+}
+
+#[get("/free/{date}")]
+async fn get_free_rooms(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    path: web::Path<String>,
+    query: web::Query<FreeQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let date = NaiveDate::parse_from_str(&path.into_inner(), UBS_DATE_FORMAT)
+        .map_err(|_| AppError::InvalidQuery("date must look like 2024-01-31".to_string()))?;
+    let (start_date, end_date) = status.lock().await.window.unwrap_or_else(|| config.fetch_window(config.today()));
+    if date < start_date || date >= end_date {
+        return Err(AppError::InvalidQuery(format!(
+            "date must be between {} and {}",
+            start_date.format(UBS_DATE_FORMAT),
+            (end_date - Duration::days(1)).format(UBS_DATE_FORMAT),
+        )));
+    }
+
+    let from = config.local_timestamp(date.and_time(FreeQuery::time(query.from.as_deref(), "from")?.unwrap_or(NaiveTime::MIN)));
+    let to = match FreeQuery::time(query.to.as_deref(), "to")? {
+        Some(to) => config.local_timestamp(date.and_time(to)),
+        None => config.local_timestamp((date + Duration::days(1)).and_time(NaiveTime::MIN)),
+    };
+    if from >= to {
+        return Err(AppError::InvalidQuery("from must be earlier than to".to_string()));
+    }
+    // Like `/lite/diff`, a day starting past the window is rejected rather than reported fully booked.
+    let until = match config.horizon(query.horizon)? {
+        Some(horizon) if Utc::now().timestamp() + horizon <= from => {
+            return Err(AppError::InvalidQuery(format!("from must be within the {} hour window", horizon / 3600)));
+        }
+        Some(horizon) => to.min(Utc::now().timestamp() + horizon),
+        None => to,
+    };
+
+    let filter = RoomFilter::parse(query.building.as_deref())?;
+    let mut rooms = HashMap::new();
+    for room in data.lock().await.values() {
+        if filter.matches(&room.name) {
+            let mut availability = room.availability(from);
+            clip_availability(&mut availability, until);
+            availability.retain(|&(start, end)| start < end);
+            rooms.insert(room.name.clone(), availability);
+        }
+    }
+
+    let rooms_json = to_json(&rooms, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(rooms_json))
+}
+
+#[get("/capacity")]
+async fn get_capacity(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    query: web::Query<CapacityQuery>,
+    building: web::Query<BuildingQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let interval = query.interval.unwrap_or(DEFAULT_CAPACITY_INTERVAL_MINUTES);
+    if interval <= 0 || interval > CAPACITY_HOURS * 60 {
+        return Err(AppError::InvalidQuery(format!("interval must be between 1 and {} minutes", CAPACITY_HOURS * 60)));
+    }
+
+    let span = match config.horizon(query.horizon)? {
+        Some(horizon) => horizon.min(CAPACITY_HOURS * 3600),
+        None => CAPACITY_HOURS * 3600,
+    };
+
+    let filter = RoomFilter::parse(building.building.as_deref())?;
+    let rooms = data.lock().await;
+    let matched: Vec<&Room> = rooms.values().filter(|room| filter.matches(&room.name)).collect();
+    let current_timestamp = Utc::now().timestamp();
+    let samples: Vec<CapacitySample> = (0..=span / (interval * 60))
+        .map(|sample| current_timestamp + sample * interval * 60)
+        .map(|t| CapacitySample {
+            t,
+            free_count: matched.iter().filter(|room| room.busy_since(t).is_none()).count(),
+        })
+        .collect();
+
+    let samples_json = to_json(&samples, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(samples_json))
+}
+
+#[get("/resources")]
+async fn get_resources(
+    req: HttpRequest,
+    config: web::Data<Arc<Config>>,
+    resources: web::Data<Arc<Mutex<Vec<i32>>>>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    config.require_admin(&req)?;
+    let resources_json = to_json(&*resources.lock().await, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(resources_json))
+}
+
+#[post("/resources/{id}")]
+async fn add_resource(
+    req: HttpRequest,
+    config: web::Data<Arc<Config>>,
+    resources: web::Data<Arc<Mutex<Vec<i32>>>>,
+    path: web::Path<i32>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    config.require_admin(&req)?;
+    let id = path.into_inner();
+    if id <= 0 {
+        return Err(AppError::InvalidResource(id));
+    }
+
+    let mut resources = resources.lock().await;
+    if resources.contains(&id) {
+        return Err(AppError::DuplicateResource(id));
+    }
+    let mut updated = resources.clone();
+    updated.push(id);
+    save_resources(&config, &updated)?;
+    *resources = updated;
+
+    let resources_json = to_json(&*resources, format.pretty)?;
+    Ok(HttpResponse::Created().content_type("application/json").body(resources_json))
+}
+
+#[delete("/resources/{id}")]
+async fn remove_resource(
+    req: HttpRequest,
+    config: web::Data<Arc<Config>>,
+    resources: web::Data<Arc<Mutex<Vec<i32>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    path: web::Path<i32>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    config.require_admin(&req)?;
+    let id = path.into_inner();
+    let mut resources = resources.lock().await;
+    let position = resources.iter().position(|&resource| resource == id).ok_or(AppError::UnknownResource(id))?;
+    let mut updated = resources.clone();
+    updated.remove(position);
+    save_resources(&config, &updated)?;
+    *resources = updated;
+    status.lock().await.forget_resource(id);
+
+    let resources_json = to_json(&*resources, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(resources_json))
+}
+
+#[get("/next-opening")]
+async fn get_next_opening(
+    config: web::Data<Arc<Config>>,
+    data: web::Data<Arc<Mutex<HashMap<RoomKey, Room>>>>,
+    status: web::Data<Arc<Mutex<CrawlStatus>>>,
+    query: web::Query<RoomsQuery>,
+    format: web::Query<FormatQuery>,
+) -> Result<HttpResponse, AppError> {
+    let horizon = config.horizon(query.horizon)?;
+    let rooms = data.lock().await;
+    let status = status.lock().await;
+    let fresh_secs = 2 * config.schedule.longest_gap() as i64;
+    let next_opening = config.next_opening(config.local_now());
+    let opens_at = config.local_timestamp(next_opening);
+    let closes_at = config.local_timestamp(next_opening.date().and_time(config.closing_time));
+    let mut room_availabilities = Vec::new();
+    let filter = RoomFilter::parse(query.building.as_deref())?;
+
+    for room in rooms.values() {
+        if filter.matches(&room.name) {
+            let mut availability = room.availability(opens_at);
+            if let Some(horizon) = horizon {
+                clip_availability(&mut availability, opens_at + horizon);
+            }
+            let availability_info = calculate_room_availability(room, &availability, opens_at, &config)?;
+            if availability_info.0 == "available" {
+                room_availabilities.push(RoomAvailability {
+                    name: room.name.clone(),
+                    status: availability_info.0,
+                    duration: availability_info.1,
+                    open: availability_info.2,
+                    busy_since: availability_info.3,
+                    confidence: status.confidence(room, Utc::now().timestamp(), fresh_secs),
+                });
+            }
+        }
+    }
+
+    room_availabilities.sort_by(|a, b| a.name.cmp(&b.name));
+    let next_opening = NextOpening { opens_at, closes_at, rooms: room_availabilities };
+    let next_opening_json = to_json(&next_opening, format.pretty)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(next_opening_json))
+}
+
+fn clip_availability(availability: &mut Vec<(i64, i64)>, until: i64) {
+    availability.retain(|&(start, _)| start < until);
+    for window in availability.iter_mut() {
+        window.1 = window.1.min(until);
+    }
+}
+
+fn next_window(availability: &[(i64, i64)], timestamp: i64, min_duration: i64) -> Option<(i64, i64)> {
+    availability
+        .iter()
+        .filter(|&&(start, end)| start < end && end > timestamp)
+        .map(|&(start, end)| (start.max(timestamp), end - start.max(timestamp)))
+        .find(|&(_, duration)| duration >= min_duration)
+}
+
+fn to_json<T: Serialize>(value: &T, pretty: bool) -> Result<String, AppError> {
+    if pretty {
+        Ok(serde_json::to_string_pretty(value)?)
+    } else {
+        Ok(serde_json::to_string(value)?)
+    }
+}
+
+// Slots are UTC timestamps (iCal `...Z`) while operating hours are wall-clock times, so the day's
+// opening and closing are converted with that day's offset and follow DST switches.
+fn calculate_room_availability(room: &Room, availability: &[(i64, i64)], current_timestamp: i64, config: &Config) -> Result<(String, i64, bool, Option<i64>), AppError> {
+    let today = config.local_date(current_timestamp);
+    let today_opening = config.local_timestamp(today.and_time(config.opening_time));
+    let today_closing = config.local_timestamp(today.and_time(config.closing_time));
+    let busy_since = room.busy_since(current_timestamp);
+    let mut open = false;
+    for &(start, end) in availability {
+        if start >= today_opening && end <= today_closing {
+            open = true;
+        }
+
+        if start <= current_timestamp && current_timestamp < end {
+            return Ok(("available".to_string(), end - current_timestamp, open, None));
+        } else if start > current_timestamp {
+            return Ok(("unavailable".to_string(), start - current_timestamp, open, busy_since));
+        }
+    }
+    Ok(("unavailable".to_string(), -1, false, busy_since))
+}
+
+async fn process_resource(
+    config: &Config,
+    resource: &i32,
+    status: &Arc<Mutex<CrawlStatus>>,
+    start_date: &chrono::NaiveDate,
+    end_date: &chrono::NaiveDate
+) -> Result<Vec<ParsedEvent>, AppError> {
+    let started = Instant::now();
+    let url = format_resource_url(config, &config.upstream_url, resource, start_date, end_date);
+    let fetched = match fetch_calendar(&config.http_client, &url).await {
+        Ok((calendar, fetched_at)) => Ok((calendar, fetched_at, "primary")),
+        Err(e) => match &config.secondary_upstream_url {
+            Some(secondary_url) => {
+                eprintln!("Primary upstream failed for resource {}: {}, falling back to secondary", resource, e);
+                let url = format_resource_url(config, secondary_url, resource, start_date, end_date);
+                let fetched = fetch_calendar(&config.http_client, &url).await;
+                if fetched.is_ok() {
+                    eprintln!("Resource {} served by secondary upstream", resource);
+                }
+                fetched.map(|(calendar, fetched_at)| (calendar, fetched_at, "secondary"))
+            }
+            None => Err(e),
+        },
+    };
+    let (calendar, fetched_at, source) = match fetched {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            // Failed fetches are timed too, so a resource stuck until the timeout shows up among the slowest.
+            status.lock().await.resource_timings.insert(*resource, ResourceTiming {
+                resource: *resource,
+                duration_ms: started.elapsed().as_millis() as u64,
+                events: 0,
+                skipped: 0,
+                source: if config.secondary_upstream_url.is_some() { "secondary" } else { "primary" },
+            });
+            return Err(e);
+        }
+    };
+
+    let stamps = calendar.events
+        .iter()
+        .filter_map(|event| event_timestamp(event, "DTSTAMP").ok())
+        .map(|stamp| stamp - fetched_at)
+        .collect();
+    if let Some(skew) = median(stamps) {
+        status.lock().await.clock_skews.insert(*resource, skew);
+    }
+
+    let window = config.window_bounds(*start_date, *end_date);
+    let mut parsed = Vec::new();
+    let mut skipped = 0;
+    for event in &calendar.events {
+        match parse_event(event, window) {
+            Ok(events) => parsed.extend(events),
+            Err(e) => {
+                let uid = event_property(event, "UID").unwrap_or_else(|| "<no uid>".to_string());
+                eprintln!("Skipping event {} of resource {}: {}", uid, resource, e);
+                skipped += 1;
+            }
+        }
+    }
+
+    status.lock().await.resource_timings.insert(*resource, ResourceTiming {
+        resource: *resource,
+        duration_ms: started.elapsed().as_millis() as u64,
+        events: calendar.events.len(),
+        skipped,
+        source,
+    });
+
+    Ok(parsed)
+}
+
+async fn fetch_calendar(client: &reqwest::Client, url: &str) -> Result<(IcalCalendar, i64), AppError> {
+    let mut attempt = 1;
+    loop {
+        match fetch_ics(client, url).await {
+            Ok((ics, fetched_at)) => return Ok((parse_calendar(&ics)?, fetched_at)),
+            Err(e) if e.is_retryable() && attempt < FETCH_ATTEMPTS => {
+                let delay = FETCH_BACKOFF_SECS << (attempt - 1);
+                eprintln!("Fetching {} failed: {}, retrying in {} seconds", url, e, delay);
+                time::sleep(time::Duration::from_secs(delay)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn fetch_ics(client: &reqwest::Client, url: &str) -> Result<(String, i64), AppError> {
+    let ics = client.get(url).send().await?.error_for_status()?.text().await?;
+    Ok((ics, Utc::now().timestamp()))
+}
+
+fn parse_calendar(ics: &str) -> Result<IcalCalendar, AppError> {
+    IcalParser::new(ics.as_bytes()).next().ok_or(AppError::ParserError)?.map_err(AppError::from)
+}
+
+fn parse_ics(ics: &str, window: (i64, i64)) -> Result<(Vec<ParsedEvent>, Vec<SkippedEvent>), AppError> {
+    let calendar = parse_calendar(ics)?;
+    let mut parsed = Vec::new();
+    let mut skipped = Vec::new();
+    for event in &calendar.events {
+        match parse_event(event, window) {
+            Ok(events) => parsed.extend(events),
+            Err(e) => skipped.push(SkippedEvent {
+                uid: event_property(event, "UID"),
+                code: e.code(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+    Ok((parsed, skipped))
+}
+
+fn format_resource_url(
+    config: &Config,
+    base_url: &str,
+    resource: &i32,
+    current_date: &chrono::NaiveDate,
+    two_weeks_date: &chrono::NaiveDate
+) -> String {
+    format!("{}?resources={}&projectId={}&calType={}&firstDate={}&lastDate={}",
+            base_url, resource, config.upstream_project_id, config.upstream_cal_type, current_date.format(UBS_DATE_FORMAT), two_weeks_date.format(UBS_DATE_FORMAT))
+}
+
+fn format_free_windows_ics(room: &Room, availability: &[(i64, i64)], current_timestamp: i64, alarm_minutes: Option<i64>) -> Result<String, AppError> {
+    let name = escape_ical_text(&room.name);
+    let uid_prefix: String = room.name.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    let stamp = format_ical_timestamp(current_timestamp)?;
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//free-room-api//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        format!("X-WR-CALNAME:{} free slots", name),
+    ];
+    for &(start, end) in availability.iter().filter(|&&(start, end)| start < end) {
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}-{}@free-room-api", uid_prefix, start));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!("DTSTART:{}", format_ical_timestamp(start)?));
+        lines.push(format!("DTEND:{}", format_ical_timestamp(end)?));
+        lines.push(format!("SUMMARY:{} free", name));
+        if let Some(minutes) = alarm_minutes {
+            lines.push("BEGIN:VALARM".to_string());
+            lines.push("ACTION:DISPLAY".to_string());
+            lines.push(format!("DESCRIPTION:{} is free", name));
+            lines.push(format!("TRIGGER:-PT{}M", minutes));
+            lines.push("END:VALARM".to_string());
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    Ok(lines.join("\r\n") + "\r\n")
+}
+
+fn format_ical_timestamp(timestamp: i64) -> Result<String, AppError> {
+    let datetime = DateTime::from_timestamp(timestamp, 0).ok_or(AppError::ParseError)?;
+    Ok(datetime.format(ICAL_DATE_FORMAT).to_string())
+}
+
+fn escape_ical_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+fn merge_events(
+    rooms: &mut HashMap<RoomKey, Room>,
+    resource: i32,
+    key_by_building: bool,
+    resource_building: Option<&str>,
+    window_end: i64,
+    events: Vec<ParsedEvent>
+) {
+    for parsed in events {
+        let key = RoomKey::new(&parsed.room, key_by_building, resource_building);
+        let (name, building) = (key.display_name(), key.building.clone());
+        let room = rooms.entry(key).or_insert_with(|| Room::new(name, building));
+        room.slots.insert((parsed.start, parsed.end));
+        room.resources.insert(resource);
+        room.window_end = window_end;
+    }
+}
+
+fn carry_over_rooms(
+    fresh: &mut HashMap<RoomKey, Room>,
+    previous: HashMap<RoomKey, Room>,
+    failed: &HashSet<i32>,
+    crawled: &HashSet<i32>,
+    window: (i64, i64)
+) {
+    for (key, mut room) in previous {
+        room.resources.retain(|resource| crawled.contains(resource));
+        if room.resources.is_empty() {
+            continue;
+        }
+        // A room whose resources all answered without booking it lost every booking: it stays listed,
+        // empty, so `/diagnostics/empty-rooms` can report it.
+        if room.resources.is_disjoint(failed) {
+            room.slots.clear();
+            room.window_end = window.1;
+            fresh.entry(key).or_insert(room);
+            continue;
+        }
+        room.slots.retain(|&(_, end)| end > window.0);
+        room.window_end = window.1;
+        match fresh.get_mut(&key) {
+            Some(fresh_room) => {
+                fresh_room.slots.extend(room.slots);
+                fresh_room.resources.extend(room.resources);
+            }
+            None => {
+                fresh.insert(key, room);
+            }
+        }
+    }
+}
+
+fn parse_event(event: &IcalEvent, window: (i64, i64)) -> Result<Vec<ParsedEvent>, AppError> {
+    let property_value = event_property(event, "LOCATION").ok_or(AppError::ParseError)?;
+    let rooms_names = property_value.split("\\,").collect::<Vec<&str>>();
+    let start = event_timestamp(event, "DTSTART")?;
+    let end = event_timestamp(event, "DTEND")?;
+    let occurrences = event_occurrences(event, start, end, window)?;
+    let summary = event_property(event, "SUMMARY");
+
+    let mut parsed = Vec::new();
+    for room_name in rooms_names {
+        for &(start, end) in &occurrences {
+            parsed.push(ParsedEvent { room: room_name.to_string(), start, end, summary: summary.clone() });
+        }
+    }
+    Ok(parsed)
+}
+
+fn event_property(event: &IcalEvent, name: &str) -> Option<String> {
+    event.properties.iter().find(|property| property.name == name).and_then(|property| property.value.clone())
+}
+
+fn event_occurrences(event: &IcalEvent, start: i64, end: i64, window: (i64, i64)) -> Result<Vec<(i64, i64)>, AppError> {
+    let rules: Vec<String> = event.properties
+        .iter()
+        .filter(|property| matches!(property.name.as_str(), "RRULE" | "RDATE" | "EXDATE"))
+        .filter_map(|property| property.value.as_ref().map(|value| format!("{}:{}", property.name, value)))
+        .collect();
+    if !rules.iter().any(|rule| rule.starts_with("RRULE:")) {
+        return Ok(vec![(start, end)]);
+    }
+
+    let rule_set: RRuleSet = format!("DTSTART:{}\n{}", format_ical_timestamp(start)?, rules.join("\n")).parse()?;
+    let duration = end - start;
+    let after = DateTime::from_timestamp(window.0 - duration, 0).ok_or(AppError::ParseError)?;
+    let before = DateTime::from_timestamp(window.1, 0).ok_or(AppError::ParseError)?;
+    let recurrences = rule_set
+        .after(after.with_timezone(&rrule::Tz::UTC))
+        .before(before.with_timezone(&rrule::Tz::UTC))
+        .all(MAX_RECURRENCES);
+    if recurrences.limited {
+        eprintln!("Recurring event expanded to more than {} occurrences, keeping the first ones", MAX_RECURRENCES);
+    }
+
+    Ok(recurrences.dates
+        .iter()
+        .map(|date| (date.timestamp(), date.timestamp() + duration))
+        .collect())
+}
+
+fn event_timestamp(event: &IcalEvent, name: &str) -> Result<i64, AppError> {
+    let values: Vec<&str> = event.properties
+        .iter()
+        .filter(|property| property.name == name)
+        .filter_map(|property| property.value.as_deref())
+        .collect();
+
+    if values.len() > 1 {
+        let uid = event_property(event, "UID").unwrap_or_else(|| "<no uid>".to_string());
+        eprintln!("Event {} has {} {} properties, keeping the first valid one", uid, values.len(), name);
+    }
+
+    let mut error = AppError::ParseError;
+    for value in values {
+        match NaiveDateTime::parse_from_str(value, ICAL_DATE_FORMAT) {
+            Ok(datetime) => return Ok(datetime.and_utc().timestamp()),
+            Err(e) => error = e.into(),
+        }
+    }
+    Err(error)
+}
+
+fn median(mut values: Vec<i64>) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    Some(values[values.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fixed defaults, so tests don't depend on FREE_ROOM_* variables set in the developer's shell.
+    fn test_config() -> Config {
+        Config {
+            bind_addr: DEFAULT_BIND_ADDR.parse().unwrap(),
+            schedule: RefreshSchedule {
+                interval: DEFAULT_REFRESH_SECS,
+                minutes: Vec::new(),
+                quiet_hours: None,
+                quiet_interval: DEFAULT_QUIET_INTERVAL_SECS,
+            },
+            admin_token: None,
+            align_weeks: false,
+            opening_time: NaiveTime::from_hms_opt(DEFAULT_OPERATING_HOURS.0, 0, 0).unwrap(),
+            closing_time: NaiveTime::from_hms_opt(DEFAULT_OPERATING_HOURS.1, 0, 0).unwrap(),
+            window_hours: None,
+            skew_warning_secs: DEFAULT_SKEW_WARNING_SECS,
+            key_by_building: true,
+            upstream_url: UBS_CALENDAR_URL.to_string(),
+            secondary_upstream_url: None,
+            refresh_cooldown_secs: DEFAULT_REFRESH_COOLDOWN_SECS,
+            initial_refresh: InitialRefresh::Background,
+            cors_max_age: Some(DEFAULT_CORS_MAX_AGE_SECS),
+            upstream_project_id: "1".to_string(),
+            upstream_cal_type: "ical".to_string(),
+            max_concurrent_per_client: DEFAULT_MAX_CONCURRENT_PER_CLIENT,
+            resources_file: None,
+            resource_buildings: HashMap::new(),
+            trusted_proxies: Vec::new(),
+            operational_timezone: DEFAULT_TIMEZONE,
+            cache_file: DEFAULT_CACHE_FILE.to_string(),
+            cache_max_age_secs: DEFAULT_CACHE_MAX_AGE_SECS,
+            health_grace_secs: DEFAULT_HEALTH_GRACE_SECS,
+            health_max_failures: DEFAULT_HEALTH_MAX_FAILURES,
+            http_client: reqwest::Client::builder()
+                .timeout(time::Duration::from_secs(DEFAULT_FETCH_TIMEOUT_SECS))
+                .build()
+                .unwrap(),
+        }
+    }
+
+    const MARCH_2024: (i64, i64) = (1709251200, 1711929600);
+
+    fn ics(events: &[&str]) -> String {
+        let mut calendar = String::from("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\n");
+        for event in events {
+            calendar.push_str("BEGIN:VEVENT\r\n");
+            for line in event.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                calendar.push_str(line);
+                calendar.push_str("\r\n");
+            }
+            calendar.push_str("END:VEVENT\r\n");
+        }
+        calendar.push_str("END:VCALENDAR\r\n");
+        calendar
+    }
+
+    fn slots(events: &[ParsedEvent]) -> Vec<(&str, i64, i64)> {
+        events.iter().map(|event| (event.room.as_str(), event.start, event.end)).collect()
+    }
+
+    #[test]
+    fn parse_ics_reads_properties_by_name_in_any_order() {
+        let calendar = ics(&[
+            "LOCATION:V-A 12\\,V-B 3
+             DESCRIPTION:TD groupe 1
+             DTEND:20240311T100000Z
+             UID:shuffled
+             SUMMARY:Maths
+             CATEGORIES:Cours
+             DTSTART:20240311T080000Z",
+            "UID:no-location
+             DTSTART:20240311T080000Z
+             DTEND:20240311T100000Z",
+        ]);
+        let (events, skipped) = parse_ics(&calendar, MARCH_2024).unwrap();
+
+        assert_eq!(slots(&events), vec![("V-A 12", 1710144000, 1710151200), ("V-B 3", 1710144000, 1710151200)]);
+        assert!(events.iter().all(|event| event.summary.as_deref() == Some("Maths")));
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].uid.as_deref(), Some("no-location"));
+        assert_eq!(skipped[0].code, "missing_property");
+    }
+
+    fn paris_config(opening_hour: u32, closing_hour: u32) -> Config {
+        let mut config = test_config();
+        config.operational_timezone = chrono_tz::Europe::Paris;
+        config.opening_time = NaiveTime::from_hms_opt(opening_hour, 0, 0).unwrap();
+        config.closing_time = NaiveTime::from_hms_opt(closing_hour, 0, 0).unwrap();
+        config
+    }
+
+    fn local(date: (i32, u32, u32), hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(date.0, date.1, date.2).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn local_timestamp_follows_the_dst_switch() {
+        let config = paris_config(8, 20);
+        assert_eq!(config.local_timestamp(local((2024, 3, 30), 8, 0)), 1711782000);
+        assert_eq!(config.local_timestamp(local((2024, 3, 31), 8, 0)), 1711864800);
+        assert_eq!(config.local_timestamp(local((2024, 3, 31), 20, 0)), 1711908000);
+        assert_eq!(config.local_timestamp(local((2024, 3, 31), 2, 30)), 1711848600);
+        assert_eq!(config.local_timestamp(local((2024, 10, 27), 2, 30)), 1729989000);
+        assert_eq!(config.local_date(1711841400), NaiveDate::from_ymd_opt(2024, 3, 31).unwrap());
+    }
+
+    #[test]
+    fn open_flag_uses_local_operating_hours_on_dst_switch_day() {
+        let config = paris_config(8, 20);
+        let room = room_with_slots(&[], 1711929600);
+        let (status, duration, open, _) = calculate_room_availability(&room, &[(1711864800, 1711908000)], 1711864800, &config).unwrap();
+        assert_eq!((status.as_str(), duration, open), ("available", 12 * 3600, true));
+
+        // A window from 07:00 local starts before opening, so it no longer fits the operating day.
+        let (_, _, open, _) = calculate_room_availability(&room, &[(1711864800 - 3600, 1711908000)], 1711864800, &config).unwrap();
+        assert!(!open);
+    }
+
+    #[test]
+    fn duplicated_dtstart_keeps_the_first_valid_value() {
+        let calendar = ics(&[
+            "UID:twice
+             DTSTART:20240311T080000Z
+             DTSTART:20240311T090000Z
+             DTEND:20240311T100000Z
+             LOCATION:V-A 12",
+            "UID:first-invalid
+             DTSTART:not-a-date
+             DTSTART:20240311T090000Z
+             DTEND:20240311T100000Z
+             LOCATION:V-A 14",
+        ]);
+        let (events, skipped) = parse_ics(&calendar, MARCH_2024).unwrap();
+        assert_eq!(slots(&events), vec![("V-A 12", 1710144000, 1710151200), ("V-A 14", 1710147600, 1710151200)]);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn weekly_rrule_expands_within_the_window() {
+        let calendar = ics(&["UID:weekly
+             DTSTART:20240226T080000Z
+             DTEND:20240226T100000Z
+             RRULE:FREQ=WEEKLY;COUNT=10
+             EXDATE:20240318T080000Z
+             LOCATION:V-A 12"]);
+        let (events, skipped) = parse_ics(&calendar, MARCH_2024).unwrap();
+        let week = 7 * 86400;
+        let starts: Vec<i64> = events.iter().map(|event| event.start).collect();
+        assert_eq!(starts, vec![1709539200, 1709539200 + week, 1709539200 + 3 * week]);
+        assert!(events.iter().all(|event| event.end - event.start == 7200 && event.room == "V-A 12"));
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn open_flag_follows_a_ten_hour_operating_day() {
+        let config = paris_config(8, 18);
+        let opening = config.local_timestamp(local((2024, 3, 11), 8, 0));
+        let closing = config.local_timestamp(local((2024, 3, 11), 18, 0));
+        assert_eq!((opening, closing), (1710140400, 1710176400));
+
+        let room = room_with_slots(&[], 1711929600);
+        assert!(calculate_room_availability(&room, &[(opening, closing)], opening, &config).unwrap().2);
+
+        let late = [(opening + 3600, closing + 3600)];
+        assert!(!calculate_room_availability(&room, &late, opening, &config).unwrap().2);
+        assert!(calculate_room_availability(&room, &late, opening, &paris_config(8, 20)).unwrap().2);
+    }
+
+    #[test]
+    fn ten_am_local_events_on_dst_switch_days_map_to_exact_utc() {
+        let config = paris_config(8, 20);
+        let calendar = ics(&[
+            "UID:spring-forward
+             DTSTART:20240331T080000Z
+             DTEND:20240331T100000Z
+             LOCATION:V-A 12",
+            "UID:fall-back
+             DTSTART:20241027T090000Z
+             DTEND:20241027T110000Z
+             LOCATION:V-A 12",
+        ]);
+        let (events, _) = parse_ics(&calendar, (1709251200, 1730419200)).unwrap();
+
+        assert_eq!(slots(&events), vec![("V-A 12", 1711872000, 1711879200), ("V-A 12", 1730019600, 1730026800)]);
+        assert_eq!(config.local_timestamp(local((2024, 3, 31), 10, 0)), 1711872000);
+        assert_eq!(config.local_timestamp(local((2024, 10, 27), 10, 0)), 1730019600);
+    }
+
+    fn room_with_slots(slots: &[(i64, i64)], window_end: i64) -> Room {
+        let mut room = Room::new("V-A 12".to_string(), None);
+        room.slots.extend(slots.iter().cloned());
+        room.window_end = window_end;
+        room
+    }
+
+    #[test]
+    fn availability_after_a_past_booking_runs_to_window_end() {
+        let room = room_with_slots(&[(100, 200)], 1000);
+        let availability = room.availability(500);
+        assert_eq!(availability, vec![(500, 1000)]);
+    }
+
+    #[test]
+    fn availability_starts_after_a_booking_spanning_now() {
+        let room = room_with_slots(&[(400, 600), (800, 900)], 1000);
+        let availability = room.availability(500);
+        assert_eq!(availability, vec![(600, 800), (900, 1000)]);
+
+        let room = room_with_slots(&[(400, 1200)], 1000);
+        let availability = room.availability(500);
+        assert!(availability.is_empty());
+    }
+
+    #[test]
+    fn availability_of_a_room_without_bookings_is_the_whole_window() {
+        let room = room_with_slots(&[], 1000);
+        let availability = room.availability(500);
+        assert_eq!(availability, vec![(500, 1000)]);
+
+        let availability = room.availability(1000);
+        assert!(availability.is_empty());
+    }
+
+    #[test]
+    fn busy_intervals_merge_duplicate_overlapping_and_adjacent_slots() {
+        let mut rooms = HashMap::new();
+        merge_events(&mut rooms, 1, true, None, 1000, vec![parsed("V-A 12", 100, 200), parsed("V-A 12", 500, 600)]);
+        merge_events(&mut rooms, 2, true, None, 1000, vec![parsed("V-A 12", 100, 200)]);
+        let room = rooms.get_mut(&RoomKey::new("V-A 12", true, None)).unwrap();
+        assert_eq!(room.busy_intervals(), vec![(100, 200), (500, 600)]);
+
+        room.slots.extend([(150, 250), (250, 300), (600, 700), (700, 700)]);
+        assert_eq!(room.busy_intervals(), vec![(100, 300), (500, 700)]);
+
+        let availability = room.availability(0);
+        assert_eq!(availability, vec![(0, 100), (300, 500), (700, 1000)]);
+    }
+
+    #[test]
+    fn availability_never_contains_windows_ended_before_reference() {
+        let room = room_with_slots(&[(100, 200), (300, 400), (600, 700)], 1000);
+        let availability = room.availability(500);
+        assert_eq!(availability, vec![(500, 600), (700, 1000)]);
+        assert!(availability.iter().all(|&(_, end)| end > 500));
+    }
+
+    #[test]
+    fn aligned_fetch_window_covers_whole_weeks() {
+        let mut config = test_config();
+        config.align_weeks = true;
+        let today = NaiveDate::from_ymd_opt(2024, 3, 13).unwrap();
+        let (start_date, end_date) = config.fetch_window(today);
+        assert_eq!(start_date, NaiveDate::from_ymd_opt(2024, 2, 26).unwrap());
+        assert_eq!(end_date, NaiveDate::from_ymd_opt(2024, 4, 29).unwrap());
+        assert_eq!(end_date.weekday(), chrono::Weekday::Mon);
+        assert_eq!((end_date - start_date).num_days() % 7, 0);
+
+        config.align_weeks = false;
+        assert_eq!(config.fetch_window(today), (NaiveDate::from_ymd_opt(2024, 2, 28).unwrap(), NaiveDate::from_ymd_opt(2024, 4, 24).unwrap()));
+    }
+
+    fn parsed(room: &str, start: i64, end: i64) -> ParsedEvent {
+        ParsedEvent { room: room.to_string(), start, end, summary: None }
+    }
+
+    #[test]
+    fn same_room_number_in_two_buildings_stays_distinct() {
+        let mut rooms = HashMap::new();
+        merge_events(&mut rooms, 1, true, Some("V-A"), 1000, vec![parsed("12", 100, 200)]);
+        merge_events(&mut rooms, 2, true, Some("V-B"), 1000, vec![parsed("12", 300, 400)]);
+        merge_events(&mut rooms, 3, true, None, 1000, vec![parsed("V-A 12", 500, 600)]);
+
+        let mut names: Vec<_> = rooms.values().map(|room| room.name.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec!["V-A 12", "V-B 12"]);
+        let room_a = &rooms[&RoomKey::new("V-A 12", true, None)];
+        assert_eq!(room_a.sorted_slots(), vec![(100, 200), (500, 600)]);
+        assert_eq!(room_a.building.as_deref(), Some("V-A"));
+        assert_eq!(rooms[&RoomKey::new("12", true, Some("V-B"))].sorted_slots(), vec![(300, 400)]);
+    }
+
+    #[test]
+    fn same_room_number_without_building_falls_back_to_name() {
+        let mut rooms = HashMap::new();
+        merge_events(&mut rooms, 1, true, None, 1000, vec![parsed("12", 100, 200)]);
+        merge_events(&mut rooms, 2, true, None, 1000, vec![parsed("12", 300, 400)]);
+        merge_events(&mut rooms, 3, false, Some("V-A"), 1000, vec![parsed("V-A 12", 500, 600), parsed("V-A12", 500, 600)]);
+        assert_eq!(rooms.len(), 3);
+        assert_eq!(rooms[&RoomKey::new("12", false, None)].sorted_slots(), vec![(100, 200), (300, 400)]);
+    }
+
+    #[test]
+    fn resources_round_trip_through_json() {
+        let resources = vec![726, 1508, 730];
+        let json = serde_json::to_string(&resources).unwrap();
+        let parsed = parse_resources(&json).unwrap();
+        assert_eq!(parsed.iter().map(|&(resource, _)| resource).collect::<Vec<_>>(), resources);
+        assert_eq!(format_resources(&resources, &HashMap::new(), true).unwrap().trim(), json);
+    }
+
+    #[test]
+    fn parse_resources_rejects_empty_and_invalid_files() {
+        assert!(parse_resources("").is_err());
+        assert!(parse_resources("  \n# only a comment\n").is_err());
+        assert!(parse_resources("[]").is_err());
+        assert!(parse_resources("726\nabc").is_err());
+        assert!(parse_resources("[726, -1]").is_err());
+    }
+
+    #[test]
+    fn room_filter_matches_building_prefix_and_number() {
+        let filter = RoomFilter::default();
+        for name in ["V-A 12", "V-A12", "V-B 3", "V-A"] {
+            assert!(filter.matches(name), "{} should match", name);
+        }
+        for name in ["v-a 12", "V-A 12B", "V-AB 1", "V-C 1", "V-A  12", " V-A 12", "12"] {
+            assert!(!filter.matches(name), "{} should not match", name);
+        }
+
+        let filter = RoomFilter::parse(Some("v-c, V-D")).unwrap();
+        assert!(filter.matches("V-C 1") && filter.matches("V-D 2") && !filter.matches("V-A 1"));
+        assert!(RoomFilter::parse(Some("VA")).is_err());
+        assert!(RoomFilter::parse(Some(" , ")).is_err());
+    }
+
+    #[test]
+    fn parse_resources_reads_buildings() {
+        assert_eq!(parse_resources("726 v-a\n# comment\n1508\n"), Ok(vec![(726, Some("V-A".to_string())), (1508, None)]));
+        assert_eq!(parse_resources(r#"[726, {"id": 1508, "building": "V-B"}]"#), Ok(vec![(726, None), (1508, Some("V-B".to_string()))]));
+        assert!(parse_resources("726 A12").is_err());
+    }
+
+    #[test]
+    fn saved_resources_file_keeps_its_format_and_buildings() {
+        let path = std::env::temp_dir().join(format!("free-room-resources-{}.txt", std::process::id()));
+        let mut config = test_config();
+        config.resources_file = Some(path.to_string_lossy().into_owned());
+        config.resource_buildings = HashMap::from([(726, "V-A".to_string())]);
+
+        for initial in ["726 V-A\n1508\n", "[726, 1508]"] {
+            std::fs::write(&path, initial).unwrap();
+            save_resources(&config, &[726, 3400]).unwrap();
+            let content = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(content.starts_with('['), initial.starts_with('['));
+            assert_eq!(parse_resources(&content), Ok(vec![(726, Some("V-A".to_string())), (3400, None)]));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cache_older_than_max_age_is_discarded() {
+        let path = std::env::temp_dir().join(format!("free-room-cache-{}.json", std::process::id()));
+        let mut config = test_config();
+        config.cache_file = path.to_string_lossy().into_owned();
+        config.cache_max_age_secs = 3600;
+        let now = Utc::now().timestamp();
+        let cache = |saved_at| RoomCache {
+            saved_at,
+            rooms: vec![CachedRoom { name: "V-A 12".to_string(), resources: vec![726], window_end: now + 7200, slots: vec![(now, now + 3600)] }],
+        };
+
+        save_cache(&config.cache_file, &cache(now - 60)).unwrap();
+        assert_eq!(load_cache(&config).len(), 1);
+        save_cache(&config.cache_file, &cache(now - 7200)).unwrap();
+        assert!(load_cache(&config).is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cached_rooms_without_upcoming_bookings_are_kept_empty() {
+        let path = std::env::temp_dir().join(format!("free-room-cache-past-{}.json", std::process::id()));
+        let mut config = test_config();
+        config.cache_file = path.to_string_lossy().into_owned();
+        let now = Utc::now().timestamp();
+        let cache = RoomCache {
+            saved_at: now - 60,
+            rooms: vec![CachedRoom { name: "V-A 12".to_string(), resources: vec![726], window_end: now + 7200, slots: vec![(now - 7200, now - 3600)] }],
+        };
+
+        save_cache(&config.cache_file, &cache).unwrap();
+        let rooms = load_cache(&config);
+        assert!(rooms[&RoomKey::new("V-A 12", config.key_by_building, None)].slots.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn confidence_drops_when_events_of_a_resource_were_skipped() {
+        let mut status = CrawlStatus::default();
+        let mut room = room_with_slots(&[(100, 200)], 1000);
+        room.resources.insert(726);
+        status.last_successes.insert(726, 1000);
+        let timing = |skipped| ResourceTiming { resource: 726, duration_ms: 10, events: 4, skipped, source: "primary" };
+
+        status.resource_timings.insert(726, timing(0));
+        assert_eq!(status.confidence(&room, 1000, 600), 1.0);
+        status.resource_timings.insert(726, timing(1));
+        assert_eq!(status.confidence(&room, 1000, 600), 0.75);
+        status.resource_failures.insert(726, ResourceFailure { resource: 726, code: "ical_parse", message: String::new(), failed_at: 1000 });
+        assert_eq!(status.confidence(&room, 1000, 600), 0.5);
+    }
+
+    #[test]
+    fn forgotten_resource_leaves_no_status_behind() {
+        let mut status = CrawlStatus::default();
+        for resource in [726, 727] {
+            status.resource_timings.insert(resource, ResourceTiming { resource, duration_ms: 10, events: 0, skipped: 0, source: "primary" });
+            status.clock_skews.insert(resource, 5);
+            status.last_successes.insert(resource, 1000);
+            status.resource_failures.insert(resource, ResourceFailure { resource, code: "timeout", message: String::new(), failed_at: 1000 });
+        }
+
+        status.forget_resource(726);
+        assert_eq!(status.resource_timings.keys().collect::<Vec<_>>(), vec![&727]);
+        assert_eq!(status.clock_skews.keys().collect::<Vec<_>>(), vec![&727]);
+        assert_eq!(status.last_successes.keys().collect::<Vec<_>>(), vec![&727]);
+        assert_eq!(status.resource_failures.keys().collect::<Vec<_>>(), vec![&727]);
+    }
+
+    #[test]
+    fn free_windows_ics_parses_back_with_and_without_alarm() {
+        let room = room_with_slots(&[(1710144000, 1710151200)], 1710172800);
+        let availability = room.availability(1710140400);
+
+        let calendar = parse_calendar(&format_free_windows_ics(&room, &availability, 1710140400, None).unwrap()).unwrap();
+        let windows: Vec<(i64, i64)> = calendar.events
+            .iter()
+            .map(|event| (event_timestamp(event, "DTSTART").unwrap(), event_timestamp(event, "DTEND").unwrap()))
+            .collect();
+        assert_eq!(windows, vec![(1710140400, 1710144000), (1710151200, 1710172800)]);
+        assert!(calendar.events.iter().all(|event| event.alarms.is_empty()));
+
+        let calendar = parse_calendar(&format_free_windows_ics(&room, &availability, 1710140400, Some(15)).unwrap()).unwrap();
+        assert_eq!(calendar.events.len(), 2);
+        for event in &calendar.events {
+            assert_eq!(event.alarms.len(), 1);
+            let trigger = event.alarms[0].properties.iter().find(|property| property.name == "TRIGGER").unwrap();
+            assert_eq!(trigger.value.as_deref(), Some("-PT15M"));
+        }
+    }
+
+    #[test]
+    fn health_tolerates_failed_refreshes_within_grace_period() {
+        let mut status = CrawlStatus::default();
+        assert!(!status.healthy(10_000, 3600, 2));
+
+        status.last_successful_refresh = Some(10_000);
+        status.failed_refreshes = 5;
+        assert!(status.healthy(12_000, 3600, 2));
+        assert!(!status.healthy(14_000, 3600, 2));
+
+        status.failed_refreshes = 2;
+        assert!(status.healthy(14_000, 3600, 2));
+        assert!(!status.healthy(14_000, 3600, 1));
+    }
+
+    #[test]
+    fn horizon_rejects_non_positive_and_huge_values() {
+        let mut config = test_config();
+        assert_eq!(config.horizon(None).unwrap(), None);
+        assert_eq!(config.horizon(Some(2)).unwrap(), Some(7200));
+        assert!(config.horizon(Some(0)).is_err());
+        assert!(config.horizon(Some(i64::MAX / 1000)).is_err());
+        assert!(config.horizon(Some(i64::MAX)).is_err());
+
+        config.window_hours = Some(3);
+        assert_eq!(config.horizon(None).unwrap(), Some(10800));
+        assert_eq!(config.horizon(Some(1)).unwrap(), Some(3600));
+    }
+
+    #[test]
+    fn client_id_trusts_forwarding_headers_only_from_trusted_proxies() {
+        let mut config = test_config();
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        assert_eq!(config.client_id(Some(client), Some("203.0.113.9")), "192.0.2.7");
+        assert_eq!(config.client_id(Some(proxy), Some("203.0.113.9")), "10.0.0.1");
+
+        config.trusted_proxies = vec![proxy];
+        assert_eq!(config.client_id(Some(proxy), Some("203.0.113.9")), "203.0.113.9");
+        assert_eq!(config.client_id(Some(client), Some("203.0.113.9")), "192.0.2.7");
+        assert_eq!(config.client_id(None, Some("203.0.113.9")), "unknown");
+    }
+
+    #[test]
+    fn rebuilt_rooms_keep_only_rooms_of_failed_resources() {
+        let mut previous = HashMap::new();
+        merge_events(&mut previous, 1, true, None, 1000, vec![parsed("V-A 12", 100, 200), parsed("V-A 12", 400, 500)]);
+        merge_events(&mut previous, 2, true, None, 1000, vec![parsed("V-A 14", 100, 200), parsed("V-A 14", 400, 500)]);
+        merge_events(&mut previous, 3, true, None, 1000, vec![parsed("V-B 1", 400, 500)]);
+
+        let mut fresh = HashMap::new();
+        merge_events(&mut fresh, 1, true, None, 2000, vec![parsed("V-A 12", 400, 500)]);
+        carry_over_rooms(&mut fresh, previous, &HashSet::from([2]), &HashSet::from([1, 2, 3]), (300, 2000));
+
+        assert_eq!(fresh.len(), 3);
+        assert_eq!(fresh[&RoomKey::new("V-A 12", true, None)].sorted_slots(), vec![(400, 500)]);
+        let carried = &fresh[&RoomKey::new("V-A 14", true, None)];
+        assert_eq!(carried.sorted_slots(), vec![(400, 500)]);
+        assert_eq!(carried.window_end, 2000);
+        let emptied = &fresh[&RoomKey::new("V-B 1", true, None)];
+        assert!(emptied.slots.is_empty());
+        assert_eq!(emptied.window_end, 2000);
+    }
+
+    #[test]
+    fn rooms_of_resources_no_longer_crawled_are_dropped() {
+        let mut previous = HashMap::new();
+        merge_events(&mut previous, 1, true, None, 1000, vec![parsed("V-A 12", 100, 200)]);
+        merge_events(&mut previous, 2, true, None, 1000, vec![parsed("V-A 14", 100, 200)]);
+
+        let mut fresh = HashMap::new();
+        carry_over_rooms(&mut fresh, previous, &HashSet::new(), &HashSet::from([1]), (300, 2000));
+
+        assert_eq!(fresh.len(), 1);
+        assert!(fresh[&RoomKey::new("V-A 12", true, None)].slots.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_are_served_while_a_refresh_waits_on_upstream() {
+        // Accepts connections through the backlog but never answers, like a stalled upstream.
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = test_config();
+        config.upstream_url = format!("http://{}/", upstream.local_addr().unwrap());
+        let config = Arc::new(config);
+
+        let mut initial = HashMap::new();
+        merge_events(&mut initial, 726, true, None, 1000, vec![parsed("V-A 12", 100, 200)]);
+        let rooms = Arc::new(Mutex::new(initial));
+        let status = Arc::new(Mutex::new(CrawlStatus::default()));
+        let resources = Arc::new(Mutex::new(vec![726]));
+
+        let crawl = tokio::spawn({
+            let (config, resources, rooms, status) = (config.clone(), resources.clone(), rooms.clone(), status.clone());
+            async move { update_rooms(&config, &resources, &rooms, &status).await }
+        });
+        time::sleep(time::Duration::from_millis(200)).await;
+        assert!(status.lock().await.refreshing);
+
+        let read = time::timeout(time::Duration::from_millis(100), rooms.lock()).await.expect("read waited on the refresh");
+        assert_eq!(read.len(), 1);
+        drop(read);
+
+        crawl.abort();
+        assert!(crawl.await.is_err());
+        assert!(!status.lock().await.refreshing);
+    }
+
+    #[tokio::test]
+    async fn refresh_guard_clears_refreshing_flag() {
+        let status = Arc::new(Mutex::new(CrawlStatus { refreshing: true, ..Default::default() }));
+        drop(RefreshGuard { status: status.clone() });
+        assert!(!status.lock().await.refreshing);
+    }
+
+    #[tokio::test]
+    async fn refresh_guard_waits_for_a_held_lock() {
+        let status = Arc::new(Mutex::new(CrawlStatus { refreshing: true, ..Default::default() }));
+        let held = status.lock().await;
+        drop(RefreshGuard { status: status.clone() });
+        drop(held);
+        time::sleep(time::Duration::from_millis(10)).await;
+        assert!(!status.lock().await.refreshing);
+    }
+}